@@ -0,0 +1,158 @@
+use crate::*;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::watch;
+use zenoh::sample::SampleKind;
+use zenoh::Session;
+
+pub const ZFS_LIVE_PREFIX: &str = "@zfs-live";
+
+static STORAGE: OnceLock<watch::Sender<bool>> = OnceLock::new();
+static DAEMONS: OnceLock<Mutex<BTreeSet<String>>> = OnceLock::new();
+
+//
+// zfsd tracks the availability of the storages and of the other daemons:
+//
+//  - the storages are the queryables on `zfs/**`. While none is reachable the
+//    jobs are `Paused`, without the sanitizer counting stalls, and they resume
+//    as soon as one is reachable again;
+//  - every zfsd declares a liveliness token on `@zfs-live/zfsd/<zid>`, thus
+//    the swarm only asks its peers for fragments when some are alive.
+//
+// Both are learnt from the declarations propagated by zenoh, hence without
+// polling. Until the tracking starts, storages and peers are assumed to be
+// reachable.
+//
+
+fn storage() -> &'static watch::Sender<bool> {
+    STORAGE.get_or_init(|| watch::Sender::new(true))
+}
+
+fn daemons() -> std::sync::MutexGuard<'static, BTreeSet<String>> {
+    DAEMONS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// True unless no storage is known to be reachable.
+pub fn zfs_storage_available() -> bool {
+    *storage().borrow()
+}
+
+/// Waits until a storage is reachable.
+pub async fn zfs_wait_storage() {
+    let mut rx = storage().subscribe();
+    let _ignore = rx.wait_for(|available| *available).await;
+}
+
+/// The ids of the other zfsd known to be alive.
+pub fn zfs_live_daemons() -> BTreeSet<String> {
+    daemons().clone()
+}
+
+/// Whether other zfsd may serve fragments, i.e. unless the tracking knows
+/// that none is alive.
+pub fn zfs_peers_alive() -> bool {
+    !zfs_config().availability.enabled || !daemons().is_empty()
+}
+
+fn set_storage_available(available: bool) {
+    storage().send_if_modified(|current| {
+        if *current == available {
+            return false;
+        }
+        if available {
+            log::info!(target: "availability", "A storage is reachable, resuming the jobs");
+        } else {
+            log::warn!(target: "availability", "No storage is reachable, pausing the jobs");
+        }
+        *current = available;
+        true
+    });
+}
+
+/// The id of the zfsd whose liveliness token is `key`, unless it is `zid`.
+fn peer_id<'a>(key: &'a str, zid: &str) -> Option<&'a str> {
+    key.strip_prefix(ZFS_LIVE_PREFIX)?
+        .strip_prefix("/zfsd/")
+        .filter(|peer| !peer.is_empty() && !peer.contains('/') && *peer != zid)
+}
+
+/// Announces this zfsd, and tracks the availability of the storages and of
+/// the other zfsd until the session is closed.
+pub async fn track_availability(z: Arc<Session>) -> ZfsResult<()> {
+    let zid = z.zid().to_string();
+    let _token = z
+        .liveliness()
+        .declare_token(format!("{}/zfsd/{}", ZFS_LIVE_PREFIX, zid))
+        .await?;
+    let peers = z
+        .liveliness()
+        .declare_subscriber(format!("{}/zfsd/*", ZFS_LIVE_PREFIX))
+        .history(true)
+        .await?;
+    let querier = z.declare_querier(format!("{}/**", zfs_base_dir())).await?;
+    let storages = querier.matching_listener().await?;
+    set_storage_available(querier.matching_status().await?.matching());
+
+    loop {
+        tokio::select! {
+            r = peers.recv_async() => {
+                let Ok(sample) = r else { break };
+                let Some(peer) = peer_id(sample.key_expr().as_str(), &zid).map(|p| p.to_string()) else {
+                    continue;
+                };
+                match sample.kind() {
+                    SampleKind::Put => {
+                        log::info!(target: "availability", "zfsd {} is alive", &peer);
+                        daemons().insert(peer);
+                    }
+                    SampleKind::Delete => {
+                        log::info!(target: "availability", "zfsd {} is gone", &peer);
+                        daemons().remove(&peer);
+                    }
+                }
+            }
+            r = storages.recv_async() => {
+                let Ok(status) = r else { break };
+                set_storage_available(status.matching());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_peer_ids() {
+        assert_eq!(peer_id("@zfs-live/zfsd/abc", "self"), Some("abc"));
+        assert_eq!(peer_id("@zfs-live/zfsd/self", "self"), None);
+        assert_eq!(peer_id("@zfs-live/zfsd/", "self"), None);
+        assert_eq!(peer_id("@zfs-live/other/abc", "self"), None);
+        assert_eq!(peer_id("@zfs-live/zfsd/a/b", "self"), None);
+    }
+
+    #[test]
+    fn wait_for_a_storage() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        assert!(zfs_storage_available(), "storages are assumed reachable until tracked");
+        set_storage_available(false);
+        assert!(!zfs_storage_available());
+        let waiting = rt.block_on(async {
+            tokio::time::timeout(std::time::Duration::from_millis(50), zfs_wait_storage()).await
+        });
+        assert!(waiting.is_err());
+        rt.block_on(async {
+            let waiter = tokio::task::spawn(zfs_wait_storage());
+            set_storage_available(true);
+            tokio::time::timeout(std::time::Duration::from_secs(5), waiter).await
+        })
+        .unwrap()
+        .unwrap();
+        assert!(zfs_storage_available());
+    }
+}
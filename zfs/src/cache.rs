@@ -0,0 +1,239 @@
+use crate::*;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+pub const CACHE_SUBDIR: &str = "cache";
+
+static CACHE: OnceLock<Mutex<FragmentCache>> = OnceLock::new();
+
+//
+// The fragment cache is shared by all the downloads of a zfsd. Fragments are
+// cached by content rather than by key: the fragment `n` of a file is stored
+// under `~/.zfsd/cache/<crc>-<size>-<fragment size>/<n>`, thus downloading
+// the same content under another key, or twice, reuses the cached fragments.
+//
+// Staged fragments are hard links to the cached ones when possible, and the
+// least recently used fragments are evicted once the cache exceeds its size.
+//
+
+/// The least recently used order of the cached fragments.
+#[derive(Default)]
+struct FragmentCache {
+    entries: HashMap<PathBuf, (u64, u64)>,
+    lru: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl FragmentCache {
+    /// Rebuilds the LRU order from the modification times of the cached
+    /// fragments, which are touched on every use.
+    fn load() -> FragmentCache {
+        let mut files = Vec::new();
+        if let Ok(dirs) = zfsd_cache_dir().and_then(|d| Ok(std::fs::read_dir(d)?)) {
+            for dir in dirs.flatten() {
+                if let Ok(entries) = std::fs::read_dir(dir.path()) {
+                    for entry in entries.flatten() {
+                        if let Ok(m) = entry.metadata() {
+                            let mtime = m.modified().unwrap_or(std::time::UNIX_EPOCH);
+                            files.push((mtime, entry.path(), m.len()));
+                        }
+                    }
+                }
+            }
+        }
+        files.sort();
+        let mut cache = FragmentCache::default();
+        for (_, path, len) in files {
+            cache.touch(path, len);
+        }
+        cache
+    }
+
+    fn touch(&mut self, path: PathBuf, len: u64) {
+        self.tick += 1;
+        if let Some((tick, old_len)) = self.entries.insert(path.clone(), (self.tick, len)) {
+            self.lru.remove(&tick);
+            self.bytes -= old_len;
+        }
+        self.lru.insert(self.tick, path);
+        self.bytes += len;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((tick, len)) = self.entries.remove(path) {
+            self.lru.remove(&tick);
+            self.bytes -= len;
+        }
+    }
+
+    /// Drops the least recently used fragments until the cache fits in
+    /// `max_bytes`, and returns their paths for the caller to remove them
+    /// once the lock is released.
+    fn evict(&mut self, max_bytes: u64) -> Vec<PathBuf> {
+        let mut evicted = Vec::new();
+        while self.bytes > max_bytes {
+            let Some((_, path)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some((_, len)) = self.entries.remove(&path) {
+                self.bytes -= len;
+            }
+            evicted.push(path);
+        }
+        evicted
+    }
+}
+
+fn remove_evicted(paths: Vec<PathBuf>) {
+    for path in paths {
+        log::debug!(target: "cache", "Evicting {:?}", &path);
+        let _ignore = std::fs::remove_file(&path);
+        if let Some(dir) = path.parent() {
+            // Only succeeds once the directory is empty
+            let _ignore = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+fn cache() -> MutexGuard<'static, FragmentCache> {
+    CACHE
+        .get_or_init(|| Mutex::new(FragmentCache::load()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn zfsd_cache_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_home()?, CACHE_SUBDIR))
+}
+
+/// The path of the fragment `n` of `digest` in the cache.
+pub fn zfsd_cached_fragment_path(digest: &FragmentationDigest, n: u32) -> ZfsResult<String> {
+    Ok(format!(
+        "{}/{:016x}-{}-{}/{}",
+        zfsd_cache_dir()?,
+        digest.crc,
+        digest.size,
+        digest.fragment_size,
+        n
+    ))
+}
+
+/// Makes `dst` a hard link to `src`, or a copy of it when linking is not
+/// possible, e.g. across file systems. `dst` is replaced atomically.
+fn link_or_copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let tmp = zfs_tmp_path(&dst.to_string_lossy());
+    if std::fs::hard_link(src, &tmp).is_err() {
+        std::fs::copy(src, &tmp)?;
+    }
+    std::fs::rename(&tmp, dst).inspect_err(|_| {
+        let _ignore = std::fs::remove_file(&tmp);
+    })
+}
+
+/// Runs the file system operations `f` of the cache out of the runtime
+/// threads.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(std::io::Error::other)?
+}
+
+/// Stages the cached fragment `n` of `digest` at `dest`. Returns false when
+/// the fragment is not cached. The cached fragment is checked against its
+/// hash when the digest records it, and against its length otherwise.
+pub async fn cache_lookup(digest: &FragmentationDigest, n: u32, dest: &str) -> bool {
+    if !zfs_config().cache.enabled {
+        return false;
+    }
+    let Ok(path) = zfsd_cached_fragment_path(digest, n).map(PathBuf::from) else {
+        return false;
+    };
+    let len = digest.fragment_len(n);
+    // The cache lock is only held to update the LRU order, not while reading
+    // or staging fragments which may be large
+    let valid = if digest.fragment_hashes.is_empty() {
+        tokio::fs::metadata(&path).await.map(|m| m.len() == len)
+    } else {
+        tokio::fs::read(&path).await.map(|bs| digest.check_fragment(n, &bs).is_ok())
+    };
+    match valid {
+        Ok(true) => {
+            let (src, dest) = (path.clone(), PathBuf::from(dest));
+            match blocking(move || {
+                link_or_copy(&src, &dest)?;
+                let _ignore = filetime::set_file_mtime(&src, filetime::FileTime::now());
+                Ok(())
+            })
+            .await
+            {
+                Ok(()) => {
+                    cache().touch(path, len);
+                    true
+                }
+                Err(e) => {
+                    log::warn!(target: "cache", "Unable to stage {:?}: {}", &path, e);
+                    false
+                }
+            }
+        }
+        Ok(false) => {
+            log::info!(target: "cache", "Discarding the invalid fragment {:?}", &path);
+            let _ignore = tokio::fs::remove_file(&path).await;
+            cache().remove(&path);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Adds the fragment `n` of `digest`, staged at `src`, to the cache and
+/// evicts the least recently used fragments if needed.
+pub async fn cache_insert(digest: &FragmentationDigest, n: u32, src: &str) {
+    let conf = &zfs_config().cache;
+    if !conf.enabled {
+        return;
+    }
+    let Ok(path) = zfsd_cached_fragment_path(digest, n).map(PathBuf::from) else {
+        return;
+    };
+    let len = digest.fragment_len(n);
+    let src = PathBuf::from(src);
+    let r = blocking(move || {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        link_or_copy(&src, &path).inspect_err(|e| log::warn!(target: "cache", "Unable to cache {:?}: {}", &src, e))?;
+        let evicted = {
+            let mut cache = cache();
+            cache.touch(path, len);
+            cache.evict(conf.max_bytes)
+        };
+        remove_evicted(evicted);
+        Ok(())
+    })
+    .await;
+    if let Err(e) = r {
+        log::debug!(target: "cache", "Not caching the fragment {} of {}: {}", n, &digest.name, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = FragmentCache::default();
+        cache.touch("a".into(), 4);
+        cache.touch("b".into(), 4);
+        cache.touch("c".into(), 4);
+        cache.touch("a".into(), 4);
+        assert_eq!(cache.bytes, 12);
+        assert_eq!(cache.evict(8), vec![PathBuf::from("b")]);
+        cache.remove(Path::new("c"));
+        assert_eq!(cache.bytes, 4);
+        assert!(cache.evict(4).is_empty());
+        assert_eq!(cache.evict(0), vec![PathBuf::from("a")]);
+        assert!(cache.entries.is_empty() && cache.lru.is_empty());
+    }
+}
@@ -0,0 +1,638 @@
+use crate::*;
+use std::sync::OnceLock;
+
+static CONFIG: OnceLock<ZfsdConfig> = OnceLock::new();
+
+///
+/// The zfsd configuration. It is read from a json5 file, e.g.:
+///
+/// {
+///   storage: { prefix: "zfs" },
+///   fragmentation: { fragment_size: 32768, adaptive: false, target_fragments: 4096, min_fragment_size: 32768, max_fragment_size: 16777216 },
+///   concurrency: { max_jobs: 16, max_inflight_fragments: 64, gap_download_schedule: 32, max_acceleration: 33 },
+///   sanitizer: { period_ms: 3000, stuck_cycles_reset: 3, fs_evt_delay_ms: 1000 },
+///   rate_limits: { download_pace_ms: 0, upload_pace_ms: 0 },
+///   logging: { level: "info", timestamps: true },
+///   gc: { period_ms: 60000, job_ttl_s: 604800, max_staging_bytes: 0 },
+///   retention: { enabled: false, period_ms: 3600000 },
+///   quotas: [ { prefix: "zfs/team-a/**", max_bytes: 2000000000000 } ],
+///   schedules: [ { prefix: "zfs/night/**", windows: ["22:00-06:00"] } ],
+///   hooks: [ { prefix: "zfs/datasets/**", kinds: ["download"], on: ["completed"], command: "/opt/bin/ingest.sh" } ],
+///   replicas: { down_rank_after: 3, down_rank_period_ms: 300000, target: 0, check_enabled: false, check_period_ms: 86400000, repair: true },
+///   scrub: { enabled: false, period_ms: 604800000, max_bps: 100000000, repair: true },
+///   swarm: { enabled: false, timeout_ms: 2000 },
+///   availability: { enabled: true },
+///   events: { enabled: true },
+///   follow: { catch_up_period_ms: 600000 },
+///   cache: { enabled: false, max_bytes: 1073741824 },
+///   shm: { enabled: false, pool_size: 67108864 },
+///   security: { signing_key: "/path/to/key", signer: "alice", trusted_signers: [], require_signature: false },
+///   zenoh: { mode: "peer" },
+/// }
+///
+/// Every section and field is optional, missing values take the defaults
+/// defined at the top of this crate.
+///
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZfsdConfig {
+    pub storage: StorageConfig,
+    pub fragmentation: FragmentationConfig,
+    pub concurrency: ConcurrencyConfig,
+    pub sanitizer: SanitizerConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub logging: LoggingConfig,
+    pub gc: GcConfig,
+    pub retention: RetentionConfig,
+    pub quotas: Vec<QuotaConfig>,
+    pub schedules: Vec<ScheduleConfig>,
+    pub hooks: Vec<HookConfig>,
+    pub replicas: ReplicasConfig,
+    pub scrub: ScrubConfig,
+    pub swarm: SwarmConfig,
+    pub availability: AvailabilityConfig,
+    pub events: EventsConfig,
+    pub follow: FollowConfig,
+    pub cache: CacheConfig,
+    pub shm: ShmConfig,
+    pub security: SecurityConfig,
+    /// The zenoh configuration used by zfsd, in the same format as `zenoh.json5`.
+    pub zenoh: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// The key prefix under which files are stored, it has to match the
+    /// `key_expr` of the zenoh storage.
+    pub prefix: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FragmentationConfig {
+    /// The fragment size used when the upload request does not specify one.
+    pub fragment_size: usize,
+    /// When true, the fragment size of the uploads that do not specify one is
+    /// picked from the size of the file, instead of `fragment_size`.
+    pub adaptive: bool,
+    /// The number of fragments aimed at by the adaptive fragment size...
+    pub target_fragments: u32,
+    /// ...within these bounds, the largest one being the largest value the
+    /// storage backend accepts.
+    pub min_fragment_size: usize,
+    pub max_fragment_size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    /// The maximum number of upload/download jobs running at the same time.
+    pub max_jobs: usize,
+    /// The maximum number of fragments downloaded at the same time, shared
+    /// between the jobs by priority.
+    pub max_inflight_fragments: usize,
+    /// The number of missing fragments the sanitizer re-schedules per cycle.
+    pub gap_download_schedule: usize,
+    /// The maximum factor applied to `gap_download_schedule` for stalled jobs.
+    pub max_acceleration: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SanitizerConfig {
+    pub period_ms: u64,
+    /// The number of cycles without progress after which gaps are re-scheduled.
+    pub stuck_cycles_reset: usize,
+    /// The delay used to let file-system events settle.
+    pub fs_evt_delay_ms: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    /// The time waited between two fragment downloads, unless the download
+    /// request sets its own pace (0 means as fast as possible).
+    pub download_pace_ms: u64,
+    /// The time waited between two fragment uploads (0 means as fast as possible).
+    pub upload_pace_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of: off, error, warn, info, debug, trace.
+    pub level: String,
+    pub timestamps: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    pub period_ms: u64,
+    /// Jobs with no progress for this long are abandoned and their staging
+    /// data removed, unless they are paused. The jobs finished for this long
+    /// are forgotten.
+    pub job_ttl_s: u64,
+    /// The maximum size of `~/.zfsd/frags` (0 means unlimited).
+    pub max_staging_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// When true, this zfsd periodically deletes the expired files from the
+    /// storage. One zfsd per storage is enough.
+    pub enabled: bool,
+    pub period_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// The key expression the quota applies to, including the storage
+    /// prefix, e.g. `zfs/team-a/**`.
+    pub prefix: String,
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// The key expression the schedule applies to, including the storage
+    /// prefix, e.g. `zfs/night/**`.
+    pub prefix: String,
+    /// The windows of the day, in local time, during which the files under
+    /// `prefix` are transferred, e.g. `22:00-06:00` or `08:00-18:00@10M`.
+    pub windows: Vec<TransferWindow>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HookConfig {
+    /// The key expression the hook applies to, including the storage prefix,
+    /// e.g. `zfs/datasets/**`.
+    pub prefix: String,
+    /// The kinds of job the hook runs for, all by default.
+    #[serde(default)]
+    pub kinds: Vec<JobKind>,
+    #[serde(flatten)]
+    pub hook: Hook,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicasConfig {
+    /// The number of recent failures after which a replica is only used
+    /// when no other one has a valid fragment. Each valid reply of the
+    /// replica forgives one failure.
+    pub down_rank_after: u32,
+    /// How long a replica stays down-ranked after its last failure, its
+    /// failures are forgotten after as long.
+    pub down_rank_period_ms: u64,
+    /// The number of replicas every file should be stored on (0 means all
+    /// the replicas seen).
+    pub target: usize,
+    /// When true, this zfsd periodically checks the replication of every
+    /// stored file. One zfsd is enough.
+    pub check_enabled: bool,
+    pub check_period_ms: u64,
+    /// When true, the under-replicated files found by the check are put again.
+    pub repair: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrubConfig {
+    /// When true, this zfsd periodically checks every stored fragment on every
+    /// replica against the hash recorded in the digest. One zfsd is enough.
+    pub enabled: bool,
+    pub period_ms: u64,
+    /// The maximum rate at which the fragments are fetched, in bits per second
+    /// (0 means unlimited).
+    pub max_bps: u64,
+    /// When true, the corrupt fragments are put again from a valid copy.
+    pub repair: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SwarmConfig {
+    /// When true, this zfsd serves the fragments it holds to its peers, and
+    /// asks them for fragments before querying the storages.
+    pub enabled: bool,
+    /// How long the peers are given to reply before the storages are queried.
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AvailabilityConfig {
+    /// When true, zfsd tracks the storages and the other zfsd reachable, and
+    /// pauses the jobs while no storage is.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// When true, zfsd publishes an event on `zfs-events/<key>` when the file
+    /// stored under `<key>` is committed, replaced or deleted.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FollowConfig {
+    /// How often the subscriptions registered with `zet --follow` list the
+    /// stored files, to catch up on the events they missed.
+    pub catch_up_period_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// When true, the downloaded fragments are kept in `~/.zfsd/cache` and
+    /// reused by the next downloads of the same content. Disabled by default
+    /// as it takes up to `max_bytes` of disk.
+    pub enabled: bool,
+    /// The least recently used fragments are evicted above this size.
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShmConfig {
+    /// When true, the fragments are put and served from zenoh shared memory,
+    /// which requires zfsd to be built with the `shm` feature.
+    pub enabled: bool,
+    /// The size of the shared memory pool, in bytes.
+    pub pool_size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// The file holding the ed25519 key used to sign the digests of the
+    /// files uploaded by this zfsd (see `zfsd --generate-key`).
+    pub signing_key: Option<String>,
+    /// The name recorded in the signatures of this zfsd.
+    pub signer: String,
+    /// The signers whose files are accepted. When not empty, files signed
+    /// by anyone else, or not signed, are refused.
+    pub trusted_signers: Vec<TrustedSigner>,
+    /// When true, unsigned files are refused even without trusted signers.
+    pub require_signature: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TrustedSigner {
+    pub name: String,
+    /// The hex encoded ed25519 public key.
+    pub public_key: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            prefix: ZFS_BASE_DIR.into(),
+        }
+    }
+}
+
+impl Default for FragmentationConfig {
+    fn default() -> Self {
+        FragmentationConfig {
+            fragment_size: FRAGMENT_SIZE,
+            adaptive: false,
+            target_fragments: TARGET_FRAGMENTS,
+            min_fragment_size: MIN_FRAGMENT_SIZE,
+            max_fragment_size: MAX_FRAGMENT_SIZE,
+        }
+    }
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            max_jobs: MAX_JOBS,
+            max_inflight_fragments: MAX_INFLIGHT_FRAGMENTS,
+            gap_download_schedule: GAP_DOWNLOAD_SCHEDULE,
+            max_acceleration: MAX_ACCELERATION,
+        }
+    }
+}
+
+impl Default for SanitizerConfig {
+    fn default() -> Self {
+        SanitizerConfig {
+            period_ms: SANITIZER_PERIOD.as_millis() as u64,
+            stuck_cycles_reset: STUCK_CYCLES_RESET,
+            fs_evt_delay_ms: FS_EVT_DELAY * 1000,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".into(),
+            timestamps: true,
+        }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            period_ms: GC_PERIOD.as_millis() as u64,
+            job_ttl_s: STAGING_TTL.as_secs(),
+            max_staging_bytes: 0,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            enabled: false,
+            period_ms: RETENTION_PERIOD.as_millis() as u64,
+        }
+    }
+}
+
+impl Default for ReplicasConfig {
+    fn default() -> Self {
+        ReplicasConfig {
+            down_rank_after: DOWN_RANK_AFTER,
+            down_rank_period_ms: DOWN_RANK_PERIOD.as_millis() as u64,
+            target: 0,
+            check_enabled: false,
+            check_period_ms: REPLICATION_CHECK_PERIOD.as_millis() as u64,
+            repair: true,
+        }
+    }
+}
+
+impl ReplicasConfig {
+    pub fn down_rank_period(&self) -> Duration {
+        Duration::from_millis(self.down_rank_period_ms)
+    }
+
+    pub fn check_period(&self) -> Duration {
+        Duration::from_millis(self.check_period_ms)
+    }
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            enabled: false,
+            period_ms: SCRUB_PERIOD.as_millis() as u64,
+            max_bps: SCRUB_MAX_BPS,
+            repair: true,
+        }
+    }
+}
+
+impl ScrubConfig {
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(self.period_ms)
+    }
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        SwarmConfig {
+            enabled: false,
+            timeout_ms: SWARM_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+impl SwarmConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+impl Default for AvailabilityConfig {
+    fn default() -> Self {
+        AvailabilityConfig { enabled: true }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig { enabled: true }
+    }
+}
+
+impl Default for FollowConfig {
+    fn default() -> Self {
+        FollowConfig {
+            catch_up_period_ms: FOLLOW_CATCH_UP_PERIOD.as_millis() as u64,
+        }
+    }
+}
+
+impl FollowConfig {
+    pub fn catch_up_period(&self) -> Duration {
+        Duration::from_millis(self.catch_up_period_ms)
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            max_bytes: CACHE_MAX_BYTES,
+        }
+    }
+}
+
+impl Default for ShmConfig {
+    fn default() -> Self {
+        ShmConfig {
+            enabled: false,
+            pool_size: SHM_POOL_SIZE,
+        }
+    }
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig {
+            signing_key: None,
+            signer: "zfsd".into(),
+            trusted_signers: vec![],
+            require_signature: false,
+        }
+    }
+}
+
+impl ZfsdConfig {
+    pub fn from_json5(s: &str) -> ZfsResult<Self> {
+        let config: ZfsdConfig = json5::from_str(s).map_err(|e| ZfsError::Config(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> ZfsResult<Self> {
+        let s = std::fs::read_to_string(path)?;
+        Self::from_json5(&s)
+    }
+
+    pub fn validate(&self) -> ZfsResult<()> {
+        if self.storage.prefix.is_empty() || self.storage.prefix.contains(['*', '$', '#', '?']) {
+            return Err(ZfsError::Config(format!(
+                "Invalid storage prefix: {:?}",
+                self.storage.prefix
+            )));
+        }
+        if self.fragmentation.fragment_size == 0 {
+            return Err(ZfsError::Config("fragment_size has to be greater than zero".into()));
+        }
+        let f = &self.fragmentation;
+        if f.target_fragments == 0 || f.min_fragment_size == 0 || f.min_fragment_size > f.max_fragment_size {
+            return Err(ZfsError::Config(
+                "The adaptive fragment sizes have to be greater than zero, with min_fragment_size <= max_fragment_size".into(),
+            ));
+        }
+        let positive = [
+            ("concurrency.max_jobs", self.concurrency.max_jobs as u64),
+            ("concurrency.max_inflight_fragments", self.concurrency.max_inflight_fragments as u64),
+            ("concurrency.gap_download_schedule", self.concurrency.gap_download_schedule as u64),
+            ("concurrency.max_acceleration", self.concurrency.max_acceleration as u64),
+            ("sanitizer.period_ms", self.sanitizer.period_ms),
+            ("sanitizer.stuck_cycles_reset", self.sanitizer.stuck_cycles_reset as u64),
+            ("gc.period_ms", self.gc.period_ms),
+            ("retention.period_ms", self.retention.period_ms),
+            ("replicas.down_rank_after", self.replicas.down_rank_after as u64),
+            ("replicas.down_rank_period_ms", self.replicas.down_rank_period_ms),
+            ("replicas.check_period_ms", self.replicas.check_period_ms),
+            ("scrub.period_ms", self.scrub.period_ms),
+            ("swarm.timeout_ms", self.swarm.timeout_ms),
+            ("follow.catch_up_period_ms", self.follow.catch_up_period_ms),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, v)| *v == 0) {
+            return Err(ZfsError::Config(format!("{} has to be greater than zero", name)));
+        }
+        for q in &self.quotas {
+            if zenoh::key_expr::KeyExpr::try_from(q.prefix.as_str()).is_err() {
+                return Err(ZfsError::Config(format!("Invalid quota prefix: {}", q.prefix)));
+            }
+        }
+        if self.shm.enabled && (!cfg!(feature = "shm") || self.shm.pool_size == 0) {
+            return Err(ZfsError::Config(
+                "shm requires zfsd to be built with the shm feature, and a pool_size greater than zero".into(),
+            ));
+        }
+        for s in &self.schedules {
+            if zenoh::key_expr::KeyExpr::try_from(s.prefix.as_str()).is_err() || s.windows.is_empty() {
+                return Err(ZfsError::Config(format!("Invalid schedule for {}", s.prefix)));
+            }
+        }
+        for h in &self.hooks {
+            if zenoh::key_expr::KeyExpr::try_from(h.prefix.as_str()).is_err() {
+                return Err(ZfsError::Config(format!("Invalid hook prefix: {}", h.prefix)));
+            }
+            h.hook
+                .validate()
+                .map_err(|e| ZfsError::Config(format!("Invalid hook for {}: {}", h.prefix, e)))?;
+        }
+        for t in &self.security.trusted_signers {
+            if hex::decode(&t.public_key).map_or(true, |bs| bs.len() != 32) {
+                return Err(ZfsError::Config(format!("Invalid public key for {}", t.name)));
+            }
+        }
+        if self.logging.level.parse::<log::LevelFilter>().is_err() {
+            return Err(ZfsError::Config(format!("Invalid log level: {}", self.logging.level)));
+        }
+        Ok(())
+    }
+
+    pub fn sanitizer_period(&self) -> Duration {
+        Duration::from_millis(self.sanitizer.period_ms)
+    }
+
+    pub fn gc_period(&self) -> Duration {
+        Duration::from_millis(self.gc.period_ms)
+    }
+
+    pub fn retention_period(&self) -> Duration {
+        Duration::from_millis(self.retention.period_ms)
+    }
+
+    pub fn fs_evt_delay(&self) -> Duration {
+        Duration::from_millis(self.sanitizer.fs_evt_delay_ms)
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter {
+        self.logging.level.parse().unwrap_or(log::LevelFilter::Info)
+    }
+
+    /// The zenoh configuration embedded in this file, or the default one.
+    pub fn zenoh_config(&self) -> ZfsResult<zenoh::Config> {
+        match &self.zenoh {
+            Some(v) => zenoh::Config::from_json5(&v.to_string()).map_err(|e| ZfsError::Config(e.to_string())),
+            None => Ok(zenoh::Config::default()),
+        }
+    }
+}
+
+/// Installs the configuration used by the whole process, once validated. It
+/// can only be set once, before zfsd starts any job.
+pub fn zfs_set_config(config: ZfsdConfig) -> ZfsResult<()> {
+    config.validate()?;
+    CONFIG
+        .set(config)
+        .map_err(|_| ZfsError::Config("The configuration has already been set".into()))
+}
+
+/// The configuration of this process, the defaults are used when none was set.
+pub fn zfs_config() -> &'static ZfsdConfig {
+    CONFIG.get_or_init(ZfsdConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_config_is_valid() {
+        let config = ZfsdConfig::from_json5(include_str!("../../zfsd.json5")).unwrap();
+        assert!(config.zenoh_config().is_ok());
+        assert!(ZfsdConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn reject_zero_values() {
+        for (section, field) in [
+            ("sanitizer", "period_ms"),
+            ("swarm", "timeout_ms"),
+            ("replicas", "down_rank_period_ms"),
+            ("concurrency", "max_jobs"),
+            ("scrub", "period_ms"),
+        ] {
+            let s = format!("{{ {}: {{ {}: 0 }} }}", section, field);
+            match ZfsdConfig::from_json5(&s) {
+                Err(ZfsError::Config(e)) => assert!(e.contains(&format!("{}.{}", section, field)), "{}", e),
+                r => panic!("{} is accepted: {:?}", s, r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn reject_invalid_values() {
+        for s in [
+            "{ storage: { prefix: \"zfs/*\" } }",
+            "{ fragmentation: { min_fragment_size: 2, max_fragment_size: 1 } }",
+            "{ quotas: [ { prefix: \"a//b\", max_bytes: 1 } ] }",
+            "{ logging: { level: \"loud\" } }",
+            "{ unknown: {} }",
+        ] {
+            assert!(ZfsdConfig::from_json5(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use tokio::sync::OwnedMutexGuard;
+
+static COORDINATOR: OnceLock<Mutex<Coordinator>> = OnceLock::new();
+
+//
+// The jobs of a zfsd share their staging directories per key, thus the jobs
+// on the same key are coordinated:
+//
+//  - only one job at a time holds a key, from its start until it completes
+//    or fails, the others wait for their turn;
+//  - a download requested while another download of the same key is in
+//    progress does not wait, it follows the leading download which, once
+//    done, reassembles the file into the path of every follower too.
+//
+
+#[derive(Default)]
+struct Coordinator {
+    locks: HashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    /// The job holding each key, by job id.
+    held: HashMap<String, (String, JobKind, OwnedMutexGuard<()>)>,
+    /// The downloads following the one in progress, by key.
+    followers: HashMap<String, Vec<String>>,
+}
+
+impl Coordinator {
+    /// The lock of `key`, `None` when `job_id` already holds a key.
+    fn lock(&mut self, job_id: &str, key: &str) -> Option<Arc<tokio::sync::Mutex<()>>> {
+        if self.held.contains_key(job_id) {
+            return None;
+        }
+        Some(self.locks.entry(key.to_string()).or_default().clone())
+    }
+
+    fn hold(&mut self, job_id: &str, kind: JobKind, key: &str, guard: OwnedMutexGuard<()>) {
+        self.held.insert(job_id.to_string(), (key.to_string(), kind, guard));
+    }
+
+    /// Adds `job_id` to the followers of the download of `key` in progress,
+    /// if any.
+    fn follow(&mut self, job_id: &str, key: &str) -> bool {
+        let leading = self
+            .held
+            .iter()
+            .any(|(id, (k, kind, _))| id != job_id && k == key && *kind == JobKind::Download);
+        if leading {
+            self.followers.entry(key.to_string()).or_default().push(job_id.to_string());
+        }
+        leading
+    }
+
+    fn is_follower(&self, job_id: &str) -> bool {
+        self.followers.values().flatten().any(|id| id == job_id)
+    }
+
+    /// Releases the key held by `job_id`, returns it if any.
+    fn release(&mut self, job_id: &str) -> Option<String> {
+        let (key, kind, guard) = self.held.remove(job_id)?;
+        drop(guard);
+        if kind == JobKind::Download {
+            self.followers.remove(&key);
+        }
+        if self.locks.get(&key).is_some_and(|l| Arc::strong_count(l) == 1) {
+            self.locks.remove(&key);
+        }
+        Some(key)
+    }
+}
+
+fn coordinator() -> MutexGuard<'static, Coordinator> {
+    COORDINATOR
+        .get_or_init(|| Mutex::new(Coordinator::default()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Waits until no other job holds `key`, then holds it for the job `job_id`
+/// until the job completes or fails.
+pub async fn zfs_acquire_key(job_id: &str, kind: JobKind, key: &str) {
+    // Keys are released when the jobs end, which requires the job database
+    if zfs_jobs().is_none() {
+        return;
+    }
+    let Some(lock) = coordinator().lock(job_id, key) else {
+        return;
+    };
+    let guard = match lock.clone().try_lock_owned() {
+        Ok(guard) => guard,
+        Err(_) => {
+            log::info!(target: "coordinator", "Job {} is waiting for the other jobs on {}", job_id, key);
+            lock.lock_owned().await
+        }
+    };
+    coordinator().hold(job_id, kind, key, guard);
+}
+
+/// Makes the download `job_id` follow the download of `key` in progress, if
+/// any. Returns false when there is none, the job then has to transfer the
+/// file itself.
+pub fn zfs_follow_download(job_id: &str, key: &str) -> bool {
+    if zfs_jobs().is_none() {
+        return false;
+    }
+    let leading = coordinator().follow(job_id, key);
+    if leading {
+        log::info!(target: "coordinator", "Download {} follows the download of {} in progress", job_id, key);
+    }
+    leading
+}
+
+/// True when the download `job_id` follows another download.
+pub fn zfs_is_follower(job_id: &str) -> bool {
+    coordinator().is_follower(job_id)
+}
+
+/// Takes the downloads following the download of `key`.
+pub fn zfs_take_followers(key: &str) -> Vec<String> {
+    coordinator().followers.remove(key).unwrap_or_default()
+}
+
+/// Releases the key held by `job_id`, if any. The followers of a download
+/// that ends without taking them are left to the sanitizer.
+pub(crate) fn zfs_release_key(job_id: &str) {
+    if let Some(key) = coordinator().release(job_id) {
+        log::debug!(target: "coordinator", "Job {} released {}", job_id, &key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Holds `key` for `job_id` if no other job does.
+    fn try_hold(c: &mut Coordinator, job_id: &str, kind: JobKind, key: &str) -> bool {
+        match c.lock(job_id, key).map(|lock| lock.try_lock_owned()) {
+            Some(Ok(guard)) => {
+                c.hold(job_id, kind, key, guard);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn hold_and_release_keys() {
+        let mut c = Coordinator::default();
+        assert!(try_hold(&mut c, "u1", JobKind::Upload, "a"));
+        assert!(!try_hold(&mut c, "u2", JobKind::Upload, "a"));
+        assert!(try_hold(&mut c, "d1", JobKind::Download, "b"));
+        // A job holds a single key
+        assert!(c.lock("u1", "c").is_none());
+
+        assert_eq!(c.release("u1").as_deref(), Some("a"));
+        assert!(c.release("u1").is_none());
+        assert!(!c.locks.contains_key("a"), "the unused locks are dropped");
+        assert!(try_hold(&mut c, "u2", JobKind::Upload, "a"));
+        assert_eq!(c.release("u2").as_deref(), Some("a"));
+        assert_eq!(c.release("d1").as_deref(), Some("b"));
+        assert!(c.held.is_empty() && c.locks.is_empty());
+    }
+
+    #[test]
+    fn follow_downloads() {
+        let mut c = Coordinator::default();
+        assert!(!c.follow("d1", "a"));
+        assert!(try_hold(&mut c, "u1", JobKind::Upload, "a"));
+        assert!(!c.follow("d1", "a"), "only downloads are followed");
+        c.release("u1");
+
+        assert!(try_hold(&mut c, "d1", JobKind::Download, "a"));
+        assert!(!c.follow("d1", "a"));
+        assert!(c.follow("d2", "a") && c.follow("d3", "a"));
+        assert!(!c.follow("d4", "b"));
+        assert!(c.is_follower("d2") && !c.is_follower("d1") && !c.is_follower("d4"));
+
+        // The followers not taken by the leader are forgotten with it
+        c.release("d1");
+        assert!(!c.is_follower("d2") && !c.is_follower("d3"));
+    }
+}
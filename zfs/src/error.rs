@@ -0,0 +1,98 @@
+use std::fmt;
+
+///
+/// The errors that can be returned by the zfs crate. The variant tells the
+/// caller what went wrong, so that it can decide whether to retry, report or
+/// give up.
+///
+#[derive(Debug)]
+pub enum ZfsError {
+    /// Local file-system error, e.g. while reading or writing a fragment.
+    Io(std::io::Error),
+    /// Error reported by the zenoh session (put, get, declarations, ...).
+    Zenoh(zenoh::Error),
+    /// A digest or payload could not be serialised or deserialised.
+    Codec(serde_json::Error),
+    /// The data does not match what the digest says (crc, size, ...).
+    Integrity(String),
+    /// The requested key, fragment or file does not exist.
+    NotFound(String),
+    /// No reply was received in time.
+    Timeout(String),
+    /// The request itself is not valid, e.g. a bad path or key.
+    Invalid(String),
+    /// The configuration could not be loaded or is not valid.
+    Config(String),
+    /// Error reported by the job database.
+    Db(sled::Error),
+    /// The upload would exceed the quota of its key prefix.
+    QuotaExceeded(String),
+    /// The digest is not signed, badly signed or signed by an untrusted key.
+    Untrusted(String),
+}
+
+pub type ZfsResult<T> = Result<T, ZfsError>;
+
+impl ZfsError {
+    /// Returns true when retrying the same operation later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ZfsError::Zenoh(_) | ZfsError::Timeout(_) | ZfsError::NotFound(_)
+        )
+    }
+}
+
+impl fmt::Display for ZfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZfsError::Io(e) => write!(f, "IO error: {}", e),
+            ZfsError::Zenoh(e) => write!(f, "Zenoh error: {}", e),
+            ZfsError::Codec(e) => write!(f, "Codec error: {}", e),
+            ZfsError::Integrity(s) => write!(f, "Integrity error: {}", s),
+            ZfsError::NotFound(s) => write!(f, "Not found: {}", s),
+            ZfsError::Timeout(s) => write!(f, "Timeout: {}", s),
+            ZfsError::Invalid(s) => write!(f, "Invalid request: {}", s),
+            ZfsError::Config(s) => write!(f, "Configuration error: {}", s),
+            ZfsError::Db(e) => write!(f, "Job database error: {}", e),
+            ZfsError::QuotaExceeded(s) => write!(f, "Quota exceeded: {}", s),
+            ZfsError::Untrusted(s) => write!(f, "Untrusted digest: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ZfsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZfsError::Io(e) => Some(e),
+            ZfsError::Zenoh(e) => Some(e.as_ref()),
+            ZfsError::Codec(e) => Some(e),
+            ZfsError::Db(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ZfsError {
+    fn from(e: std::io::Error) -> Self {
+        ZfsError::Io(e)
+    }
+}
+
+impl From<zenoh::Error> for ZfsError {
+    fn from(e: zenoh::Error) -> Self {
+        ZfsError::Zenoh(e)
+    }
+}
+
+impl From<serde_json::Error> for ZfsError {
+    fn from(e: serde_json::Error) -> Self {
+        ZfsError::Codec(e)
+    }
+}
+
+impl From<sled::Error> for ZfsError {
+    fn from(e: sled::Error) -> Self {
+        ZfsError::Db(e)
+    }
+}
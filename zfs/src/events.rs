@@ -0,0 +1,98 @@
+use crate::*;
+use zenoh::Session;
+
+//
+// zfsd publishes an event on `zfs-events/<key>` whenever the file stored
+// under `<key>` changes: when an upload is committed, replacing the previous
+// version or not, and when the file is deleted. Applications subscribe to
+// `zfs-events/**`, or to a narrower key expression, rather than polling the
+// storage, e.g.:
+//
+//   { "kind": "replaced", "key": "test/zut", "generation": "…", "size": 1234,
+//     "crc": 42, "fragments": 1, "fragment_size": 32768, "signer": "alice",
+//     "uploader": "<zid of the zfsd>", "previous_generation": "…",
+//     "time": 1700000000 }
+//
+// Events are not stored, a subscriber only receives the ones published while
+// it is connected.
+//
+
+pub const ZFS_EVENTS_PREFIX: &str = "zfs-events";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZfsEventKind {
+    /// A file was stored under a key that had none.
+    Committed,
+    /// A new version of the file replaced the previous one.
+    Replaced,
+    Deleted,
+}
+
+///
+/// A change of the file stored under `key`, with the summary of its digest,
+/// i.e. of the new version, or of the deleted one.
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ZfsEvent {
+    pub kind: ZfsEventKind,
+    pub key: String,
+    pub generation: Option<String>,
+    pub size: u64,
+    pub crc: u64,
+    pub fragments: u32,
+    pub fragment_size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// The signer named by the digest, if signed. It is not verified: the
+    /// event itself is not signed, thus subscribers have to fetch the digest
+    /// and check it with `verify_digest` before trusting the signer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+    /// The zenoh id of the zfsd that published the event.
+    pub uploader: String,
+    /// The generation replaced, for `Replaced` events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_generation: Option<String>,
+    /// Seconds since the UNIX epoch.
+    pub time: u64,
+}
+
+impl ZfsEvent {
+    pub fn new(z: &Session, kind: ZfsEventKind, key: &str, digest: &FragmentationDigest) -> ZfsEvent {
+        ZfsEvent {
+            kind,
+            key: key.to_string(),
+            generation: digest.generation.clone(),
+            size: digest.size,
+            crc: digest.crc,
+            fragments: digest.fragments,
+            fragment_size: digest.fragment_size,
+            expires: digest.expires,
+            signer: digest.signature.as_ref().map(|s| s.signer.clone()),
+            uploader: z.zid().to_string(),
+            previous_generation: None,
+            time: zfs_now(),
+        }
+    }
+}
+
+/// The key on which the events of the file stored under `key` are published.
+pub fn zfs_events_key(key: &str) -> String {
+    format!("{}/{}", ZFS_EVENTS_PREFIX, key)
+}
+
+/// Publishes `event`, failures are only logged as the change itself is done.
+pub async fn publish_event(z: &Session, event: &ZfsEvent) {
+    if !zfs_config().events.enabled {
+        return;
+    }
+    let r = match serde_json::to_vec(event) {
+        Ok(bs) => z.put(zfs_events_key(&event.key), bs).await.map_err(ZfsError::from),
+        Err(e) => Err(e.into()),
+    };
+    match r {
+        Ok(()) => log::debug!(target: "events", "Published {:?} for {}", event.kind, &event.key),
+        Err(e) => log::warn!(target: "events", "Unable to publish the {:?} event of {}: {}", event.kind, &event.key, e),
+    }
+}
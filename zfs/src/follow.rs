@@ -0,0 +1,267 @@
+use crate::*;
+use checksum::crc::Crc;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zenoh::key_expr::KeyExpr;
+use zenoh::Session;
+
+//
+// `zet --follow KEY_EXPR -p DIR` registers a standing subscription, stored as
+// a follow digest under `~/.zfsd/digest/follow`: zfsd downloads every file
+// committed under the key expression into the same relative path under DIR,
+// e.g. `datasets/cam1/2024/a.mp4` into `/data/cam1/2024/a.mp4` when following
+// `datasets/cam1/**`.
+//
+// The new files are learnt from the events published on `zfs-events/**`. The
+// version of each file downloaded is kept under `~/.zfsd/follow`, thus the
+// stored files are also listed when the subscription starts, e.g. after a
+// restart, and then periodically, to catch up on the files committed while
+// zfsd was not running or whose event was missed.
+//
+// A replaced file is downloaded again, the local copy of a deleted one is
+// kept. The subscription ends when its follow digest is removed, see
+// `zet --unfollow`.
+//
+
+pub fn zfsd_follow_state_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_home()?, FOLLOW_SUBDIR))
+}
+
+pub fn zfsd_follow_state_path(id: &str) -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_follow_state_dir()?, id))
+}
+
+///
+/// The files downloaded by a subscription, with the generation (or the crc
+/// of ungenerated files) of their last version.
+///
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct FollowState {
+    files: BTreeMap<String, String>,
+    /// The number of downloads requested, used to name them.
+    requested: u64,
+}
+
+impl FollowState {
+    async fn load(id: &str) -> FollowState {
+        let Ok(path) = zfsd_follow_state_path(id) else {
+            return FollowState::default();
+        };
+        match tokio::fs::read(path).await {
+            Ok(bs) => serde_json::from_slice(&bs).unwrap_or_else(|e| {
+                log::warn!(target: "follow", "Invalid state for subscription {}, starting afresh: {}", id, e);
+                FollowState::default()
+            }),
+            Err(_) => FollowState::default(),
+        }
+    }
+
+    async fn save(&self, id: &str) -> ZfsResult<()> {
+        write_atomically(&zfsd_follow_state_path(id)?, &serde_json::to_vec(self)?).await
+    }
+}
+
+fn version(generation: Option<&str>, crc: u64) -> String {
+    generation.map_or_else(|| format!("{:x}", crc), |g| g.to_string())
+}
+
+/// The local path of `key` for the subscription `spec`: the chunks of the key
+/// matched by the wildcards of the key expression, under `spec.path`.
+fn mirror_path(spec: &FollowDigest, key: &str) -> Option<PathBuf> {
+    let chunks: Vec<&str> = key.split('/').collect();
+    let fixed = spec
+        .key_expr
+        .split('/')
+        .take_while(|c| !c.contains(['*', '$']))
+        .count()
+        .min(chunks.len() - 1);
+    let rel = &chunks[fixed..];
+    // Not escaping the mirrored directory
+    if rel.iter().any(|c| c.is_empty() || *c == "." || *c == "..") {
+        return None;
+    }
+    Some(Path::new(&spec.path).join(rel.join("/")))
+}
+
+/// Whether the file at `path` has the given size and crc.
+pub(crate) async fn is_up_to_date(path: &Path, size: u64, crc: u64) -> bool {
+    if tokio::fs::metadata(path).await.map(|m| m.len()).ok() != Some(size) {
+        return false;
+    }
+    let path = path.to_path_buf();
+    let local = tokio::task::spawn_blocking(move || Crc::new(&path.to_string_lossy()).checksum().map(|c| c.crc64).ok())
+        .await
+        .ok()
+        .flatten();
+    local == Some(crc)
+}
+
+struct Follower {
+    id: String,
+    spec: FollowDigest,
+    state: FollowState,
+}
+
+impl Follower {
+    /// Requests the download of the version `version` of `key`, whose size
+    /// and crc are given, unless it is the one already downloaded.
+    async fn update(&mut self, key: &str, version: String, size: u64, crc: u64) -> ZfsResult<()> {
+        if self.state.files.get(key) == Some(&version) {
+            return Ok(());
+        }
+        let Some(path) = mirror_path(&self.spec, key) else {
+            log::warn!(target: "follow", "Not following {}, it has no valid local path under {}", key, &self.spec.path);
+            return Ok(());
+        };
+        if path.exists() {
+            // E.g. downloaded before the subscription started
+            if is_up_to_date(&path, size, crc).await {
+                log::debug!(target: "follow", "{:?} is already up to date", &path);
+                self.state.files.insert(key.to_string(), version);
+                return self.state.save(&self.id).await;
+            }
+            // Kept until the new version is downloaded
+            log::info!(target: "follow", "{} has been replaced, downloading it again into {:?}", key, &path);
+        }
+        let download = DownloadDigest {
+            key: key.to_string(),
+            path: path.to_string_lossy().to_string(),
+            pace: 0,
+            preserve: self.spec.preserve,
+            priority: self.spec.priority,
+            windows: self.spec.windows.clone(),
+            hooks: self.spec.hooks.clone(),
+            replace: true,
+        };
+        let job_id = format!("{}-{}", &self.id, self.state.requested);
+        self.state.requested += 1;
+        self.state.files.insert(key.to_string(), version);
+        // Not downloaded twice if zfsd stops in between
+        self.state.save(&self.id).await?;
+        log::info!(target: "follow", "Downloading {} into {:?}", key, &path);
+        write_atomically(&zfsd_download_digest_path(&job_id)?, &serde_json::to_vec(&download)?).await
+    }
+
+    /// Requests the download of the stored files that changed since they were
+    /// last downloaded.
+    async fn catch_up(&mut self, z: &Session, key_expr: &KeyExpr<'_>) -> ZfsResult<()> {
+        log::debug!(target: "follow", "Catching up on {}", &self.spec.key_expr);
+        for (key, digest) in list_stored_digests(z).await? {
+            if is_followed(key_expr, &key) {
+                let version = version(digest.generation.as_deref(), digest.crc);
+                self.update(&key, version, digest.size, digest.crc).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// True when the file stored under `key` matches the followed `key_expr`.
+fn is_followed(key_expr: &KeyExpr<'_>, key: &str) -> bool {
+    KeyExpr::try_from(key).is_ok_and(|k| key_expr.includes(&k))
+}
+
+/// Runs the subscription described by the follow digest at `path` until the
+/// digest is removed.
+pub async fn follow(z: Arc<Session>, path: PathBuf) -> ZfsResult<()> {
+    let bs = tokio::fs::read(&path).await?;
+    let spec = serde_json::from_slice::<FollowDigest>(&bs)?;
+    let key_expr = KeyExpr::try_from(spec.key_expr.clone())
+        .map_err(|e| ZfsError::Invalid(format!("Invalid key expression {}: {}", &spec.key_expr, e)))?;
+    let id = zfs_job_id(&path);
+    let mut follower = Follower {
+        state: FollowState::load(&id).await,
+        id,
+        spec,
+    };
+    log::info!(target: "follow", "Following {} into {}", &follower.spec.key_expr, &follower.spec.path);
+
+    // Declared before catching up, not to miss the files committed meanwhile
+    let events = z.declare_subscriber(zfs_events_key(&follower.spec.key_expr)).await?;
+    let mut catch_up = tokio::time::interval(zfs_config().follow.catch_up_period());
+    loop {
+        let r = tokio::select! {
+            _ = catch_up.tick() => {
+                if !path.exists() {
+                    break;
+                }
+                follower.catch_up(&z, &key_expr).await
+            }
+            r = events.recv_async() => {
+                let Ok(sample) = r else { break };
+                if !path.exists() {
+                    break;
+                }
+                match serde_json::from_slice::<ZfsEvent>(&sample.payload().to_bytes()) {
+                    Ok(e) if e.kind == ZfsEventKind::Deleted => Ok(()),
+                    // The key of the event is not to be trusted, it may not be the one it was published on
+                    Ok(e) if !is_followed(&key_expr, &e.key) => {
+                        log::debug!(target: "follow", "Ignoring the event of {} on {}", &e.key, sample.key_expr());
+                        Ok(())
+                    }
+                    Ok(e) => follower.update(&e.key, version(e.generation.as_deref(), e.crc), e.size, e.crc).await,
+                    Err(e) => {
+                        log::warn!(target: "follow", "Invalid event on {}: {}", sample.key_expr(), e);
+                        Ok(())
+                    }
+                }
+            }
+        };
+        if let Err(e) = r {
+            log::warn!(target: "follow", "Subscription {} failed to update: {}", &follower.spec.key_expr, e);
+        }
+    }
+    log::info!(target: "follow", "No longer following {}", &follower.spec.key_expr);
+    let _ignore = tokio::fs::remove_file(zfsd_follow_state_path(&follower.id)?).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(key_expr: &str) -> FollowDigest {
+        FollowDigest {
+            key_expr: key_expr.into(),
+            path: "/data".into(),
+            preserve: PreserveFlags::default(),
+            priority: Priority::default(),
+            windows: vec![],
+            hooks: vec![],
+        }
+    }
+
+    #[test]
+    fn follow_the_matching_keys_only() {
+        let key_expr = KeyExpr::try_from("datasets/cam1/**").unwrap();
+        assert!(is_followed(&key_expr, "datasets/cam1/a/b"));
+        assert!(!is_followed(&key_expr, "datasets/cam2/a"));
+        assert!(!is_followed(&key_expr, "datasets//cam1/a"));
+    }
+
+    #[test]
+    fn mirror_path_under_the_wildcards() {
+        let s = spec("datasets/cam1/**");
+        assert_eq!(mirror_path(&s, "datasets/cam1/2024/a.mp4"), Some(PathBuf::from("/data/2024/a.mp4")));
+        assert_eq!(mirror_path(&s, "datasets/cam1/a.mp4"), Some(PathBuf::from("/data/a.mp4")));
+        let s = spec("datasets/*/raw/**");
+        assert_eq!(mirror_path(&s, "datasets/cam1/raw/a"), Some(PathBuf::from("/data/cam1/raw/a")));
+        let s = spec("datasets/cam$*/**");
+        assert_eq!(mirror_path(&s, "datasets/cam2/a"), Some(PathBuf::from("/data/cam2/a")));
+        // The last chunk is always kept, even without wildcards
+        let s = spec("datasets/cam1/a.mp4");
+        assert_eq!(mirror_path(&s, "datasets/cam1/a.mp4"), Some(PathBuf::from("/data/a.mp4")));
+    }
+
+    #[test]
+    fn mirror_path_stays_under_the_directory() {
+        let s = spec("datasets/**");
+        assert_eq!(mirror_path(&s, "datasets/../../etc/passwd"), None);
+        assert_eq!(mirror_path(&s, "datasets/a/../../b"), None);
+        assert_eq!(mirror_path(&s, "datasets/./a"), None);
+        assert_eq!(mirror_path(&s, "datasets/a//b"), None);
+        assert_eq!(mirror_path(&s, "datasets/.."), None);
+        assert_eq!(mirror_path(&s, "datasets/..a/b.."), Some(PathBuf::from("/data/..a/b..")));
+    }
+}
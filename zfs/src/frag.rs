@@ -1,136 +1,390 @@
 use crate::*;
 use tokio::fs::{create_dir_all, File};
 use checksum::crc::Crc;
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Picks the fragment size of a file of `size` bytes so that it has about
+/// `fragmentation.target_fragments` fragments. The size is a power of two
+/// within the configured bounds, and small enough for a fragment to fit in
+/// the largest message accepted by the zenoh transport.
+pub fn zfs_adaptive_fragment_size(z: &zenoh::Session, size: u64) -> usize {
+    let max_message_size = z
+        .config()
+        .get_typed::<usize>("transport/link/rx/max_message_size")
+        .unwrap_or(usize::MAX);
+    adaptive_fragment_size(&zfs_config().fragmentation, max_message_size, size)
+}
+
+fn adaptive_fragment_size(conf: &FragmentationConfig, max_message_size: usize, size: u64) -> usize {
+    let mut max = conf.max_fragment_size;
+    if let Some(room) = max_message_size.checked_sub(FRAGMENT_MSG_OVERHEAD).filter(|r| *r > 0) {
+        // The largest power of two that fits
+        max = max.min(1 << room.ilog2());
+    }
+    let wanted = size.div_ceil(conf.target_fragments as u64).next_power_of_two();
+    let wanted = usize::try_from(wanted).unwrap_or(usize::MAX);
+    wanted.clamp(conf.min_fragment_size.min(max), max)
+}
+
+/// The hash recorded in the fragmentation digest for a fragment holding `bs`.
+pub fn zfs_fragment_hash(bs: &[u8]) -> String {
+    hex::encode(Sha256::digest(bs))
+}
 
 pub async fn fragment(
     file_path: &str,
     zkey: &str,
     fragment_size: usize,
-) -> Result<crate::FragmentationDigest, String> {
+    adaptive_fragment_size: bool,
+    expires: Option<u64>,
+    generation: Option<&str>,
+    preserve: PreserveFlags,
+) -> ZfsResult<crate::FragmentationDigest> {
+    if fragment_size == 0 {
+        return Err(ZfsError::Invalid("The fragment size has to be greater than zero".into()));
+    }
     match Crc::new(file_path).checksum() {
         Ok(checksum) => {
             let mut file = match File::open(file_path).await {
                 Ok(f) => f,
-                Err(_) => return Err(format!("Unable to open the file {}", file_path)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(ZfsError::NotFound(format!("Unable to open the file {}", file_path)))
+                }
+                Err(e) => return Err(e.into()),
             };
             let mut bs = vec![0_u8; fragment_size];
             log::debug!("bs.len() = {}", bs.len());
             let mut fid = 0;
-            let frag_path = zfsd_upload_frags_dir_for_key(zkey);
+            let mut zero_fragments: Vec<(u32, u32)> = Vec::new();
+            let mut fragment_hashes = Vec::new();
+            let frag_path = zfsd_upload_frags_dir_for_key(zkey)?;
             log::debug!("Target dir: {:?}", frag_path);
-            create_dir_all(Path::new(&frag_path)).await.unwrap();
+            create_dir_all(Path::new(&frag_path)).await?;
+            let fragments = file.metadata().await?.len().div_ceil(fragment_size as u64);
+            remove_stale_fragments(&frag_path, fragments).await?;
             loop {
-                match file.read(&mut bs).await {
-                    Ok(n) if n > 0 => {
-                        let fname = format!("{}/{}", &frag_path, fid);
-                        let mut f = match File::create(&fname).await {
-                            Ok(f) => f,
-                            Err(e) => {
-                                log::debug!(
-                                    "Error {:?} while creating the fragment: {}",
-                                    e,
-                                    &fname
-                                );
-                                panic!("IO Error")
-                            }
-                        };
-                        let _ignore = f.write(&bs[0..n]).await;
-                        fid += 1;
+                let n = read_full(&mut file, &mut bs).await?;
+                if n == 0 {
+                    break;
+                }
+                let fname = format!("{}/{}", &frag_path, fid);
+                if bs[0..n].iter().all(|b| *b == 0) {
+                    // Not stored, it may be left over by a previous fragmentation
+                    let _ignore = tokio::fs::remove_file(&fname).await;
+                    match zero_fragments.last_mut() {
+                        Some((first, count)) if *first + *count == fid => *count += 1,
+                        _ => zero_fragments.push((fid, 1)),
                     }
-                    _ => break,
+                    fragment_hashes.push(String::new());
+                } else {
+                    fragment_hashes.push(zfs_fragment_hash(&bs[0..n]));
+                    write_atomically(&fname, &bs[0..n]).await.map_err(|e| {
+                        log::debug!("Error {:?} while creating the fragment: {}", e, &fname);
+                        e
+                    })?;
                 }
+                fid += 1;
             }
 
-            let digest = crate::FragmentationDigest {
+            let mut digest = crate::FragmentationDigest {
                 name: zkey.into(),
-                size: file.metadata().await.unwrap().len(),
+                size: file.metadata().await?.len(),
                 crc: checksum.crc64,
                 fragment_size,
                 fragments: fid,
+                expires,
+                signature: None,
+                generation: generation.map(|g| g.to_string()),
+                metadata: if preserve.is_empty() {
+                    None
+                } else {
+                    Some(capture_metadata(file_path, preserve)?)
+                },
+                zero_fragments,
+                adaptive_fragment_size,
+                fragment_hashes,
             };
+            sign_digest(&mut digest)?;
             log::debug!("{:?}", digest);
             write_defrag_digest(&digest, &frag_path)
                 .await
                 .map(|_| digest)
         }
-        Err(e) => Err(e.into()),
+        Err(e) => Err(ZfsError::Integrity(format!(
+            "Unable to compute the checksum of {}: {}",
+            file_path, e
+        ))),
     }
 }
 
-pub async fn fragment_from_digest(path: String) -> Result<(), String> {
-    let path = PathBuf::from(path);
-    let mut target = PathBuf::from(path.parent().unwrap());
-    target.push(crate::FRAGS_SUBDIR);
+/// Removes the fragments numbered `fragments` or above staged in `frag_path`,
+/// left over by a previous upload of a larger file on the same key.
+async fn remove_stale_fragments(frag_path: &str, fragments: u64) -> ZfsResult<()> {
+    let mut entries = tokio::fs::read_dir(frag_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.parse::<u64>().is_ok_and(|n| n >= fragments) {
+            log::debug!("Removing the stale fragment {:?}", entry.path());
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
 
-    let bs = std::fs::read(path.as_path()).unwrap();
-    let upload_spec = match serde_json::from_slice::<crate::UploadDigest>(&bs) {
-        Ok(us) => us,
-        Err(e) => return Err(format!("{:?}", e)),
-    };
+pub async fn fragment_from_digest(z: std::sync::Arc<zenoh::Session>, path: String) -> ZfsResult<()> {
+    // The job id is the generation of the upload
+    if zfs_jobs().is_none() {
+        return Err(ZfsError::Config("Uploading needs the job database".into()));
+    }
+    let bs = tokio::fs::read(Path::new(&path)).await?;
+    let upload_spec = serde_json::from_slice::<crate::UploadDigest>(&bs)?;
     log::debug!(target: "zfsd", "Uploading: {} as {}", &upload_spec.path, &upload_spec.key);
+    let job_id = zfs_job_id(Path::new(&path));
+    let mut job = Job::new(&job_id, JobKind::Upload, &upload_spec.key, &upload_spec.path);
+    job.priority = upload_spec.priority;
+    job.windows = upload_spec.windows.clone();
+    job.hooks = upload_spec.hooks.clone();
     if !std::path::Path::new(&upload_spec.path).exists() {
         log::warn!(target: "zfsd", "The file {} does not exit", &upload_spec.path);
+        job.state = JobState::Failed;
+        job.error = Some("The file does not exist".into());
+        zfs_put_job(&job);
         return Ok(());
     }
-    crate::frag::fragment(
+    job.size = tokio::fs::metadata(&upload_spec.path).await?.len();
+    zfs_put_job(&job);
+    // The upload staging of the key is shared with the other uploads
+    zfs_acquire_key(&job_id, JobKind::Upload, &upload_spec.key).await;
+    // Held until the upload is committed or paused
+    let _permit = zfs_admit_job(&job_id, upload_spec.priority).await;
+    if let Err(e) = check_quota(&z, &upload_spec.key, &job_id, job.size).await {
+        log::warn!(target: "zfsd", "Refusing to upload {}: {}", &upload_spec.path, e);
+        zfs_update_job(&job_id, |j| {
+            j.state = JobState::Failed;
+            j.error = Some(e.to_string());
+        });
+        return Err(e);
+    }
+    zfs_update_job(&job_id, |j| j.state = JobState::Fragmenting);
+
+    let conf = &zfs_config().fragmentation;
+    let (fragment_size, adaptive) = if upload_spec.fragment_size > 0 {
+        (upload_spec.fragment_size, false)
+    } else if upload_spec.adaptive_fragment_size || conf.adaptive {
+        (zfs_adaptive_fragment_size(&z, job.size), true)
+    } else {
+        (conf.fragment_size, false)
+    };
+    log::info!(target: "zfsd", "Fragmenting {} into {} bytes fragments", &upload_spec.path, fragment_size);
+    match crate::frag::fragment(
         &upload_spec.path,
         &upload_spec.key,
-        upload_spec.fragment_size,
+        fragment_size,
+        adaptive,
+        upload_spec.expires,
+        Some(&job_id),
+        upload_spec.preserve,
     )
     .await
-    .unwrap();
-    Ok(())
+    .and_then(|digest| Ok((digest.stored_fragment_count()?, digest)))
+    {
+        Ok((fragments, digest)) => {
+            zfs_update_job(&job_id, |j| {
+                j.size = digest.size;
+                j.fragments = fragments;
+                j.signer = digest.signature.as_ref().map(|s| s.signer.clone());
+                j.state = JobState::Transferring;
+            });
+            upload_job(&z, &job_id).await
+        }
+        Err(e) => {
+            zfs_update_job(&job_id, |j| {
+                j.state = JobState::Failed;
+                j.error = Some(e.to_string());
+            });
+            Err(e)
+        }
+    }
 }
 
-pub async fn read_defrag_digest(base_path: &str) -> Result<FragmentationDigest, String> {
+pub async fn read_defrag_digest(base_path: &str) -> ZfsResult<FragmentationDigest> {
     let path: PathBuf = [base_path, crate::ZFS_DIGEST].iter().collect();
     log::debug!("read_defrag_digest: Trying to read: {:?}", &path.as_path());
-    let rbs = tokio::fs::read(path.as_path()).await;
+    let bs = tokio::fs::read(path.as_path()).await?;
     log::debug!(
         "read_defrag_digest: Trying to deserialize: {:?}",
         &path.as_path()
     );
-    rbs.map_err(|e| format!("{:?}", e)).and_then(|bs| {
-        match serde_json::from_slice::<crate::FragmentationDigest>(&bs) {
-            Ok(digest) => Ok(digest),
-            Err(e) => Err(format!("{:?}", e)),
-        }
-    })
+    zfs_decode_digest(&bs)
 }
 
 pub async fn write_defrag_digest(
     digest: &FragmentationDigest,
     base_path: &str,
-) -> Result<(), String> {
-    let bs = serde_json::to_vec(&digest).unwrap();
+) -> ZfsResult<()> {
+    let bs = zfs_encode_digest(digest)?;
     let digest_path = format!("{}/{}", base_path, ZFS_DIGEST);
-    let mut fdigest = File::create(Path::new(&digest_path)).await.unwrap();
-    fdigest.write_all(&bs).await.unwrap();
+    write_atomically(&digest_path, &bs).await
+}
+
+/// Reads from `file` until `bs` is full or the end of the file is reached, so
+/// that every fragment but the last one is exactly `bs.len()` bytes long.
+async fn read_full(file: &mut File, bs: &mut [u8]) -> ZfsResult<usize> {
+    let mut n = 0;
+    while n < bs.len() {
+        match file.read(&mut bs[n..]).await? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    Ok(n)
+}
+
+/// Writes `bs` into `path` through a temporary file which is synced and then
+/// renamed, thus after a crash `path` is either missing or complete.
+pub async fn write_atomically(path: &str, bs: &[u8]) -> ZfsResult<()> {
+    let tmp = zfs_tmp_path(path);
+    let r = async {
+        let mut f = File::create(&tmp).await?;
+        f.write_all(bs).await?;
+        f.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+    .await;
+    if r.is_err() {
+        let _ignore = tokio::fs::remove_file(&tmp).await;
+    }
+    r?;
+    sync_parent_dir(path).await;
     Ok(())
 }
-pub async fn defragment(key: &str, dest: &str) -> Result<bool, String> {
-    let fragments_path = zfsd_download_frags_dir_for_key(key);
-
-    match read_defrag_digest(&fragments_path).await {
-        Ok(digest) => {
-            let dest_path = Path::new(dest);
-            let dest_dir = dest_path.parent().unwrap().to_str().unwrap().to_string();
-            create_dir_all(Path::new(&dest_dir)).await.unwrap();
-
-            let mut f = File::create(Path::new(&dest)).await.unwrap();
-            for i in 0..digest.fragments {
-                let frag_path = format!("{}/{}", fragments_path, i);
-                let bs = std::fs::read(Path::new(&frag_path)).unwrap();
-                f.write_all(&bs).await.unwrap();
+
+/// Makes the rename of a file into `path` durable.
+async fn sync_parent_dir(path: &str) {
+    if let Some(parent) = Path::new(path).parent() {
+        if let Ok(dir) = File::open(parent).await {
+            let _ignore = dir.sync_all().await;
+        }
+    }
+}
+
+/// Reassembles the fragments downloaded for `key` into `dest`. Returns
+/// `Ok(true)` when the crc of the reassembled file matches the digest.
+///
+/// The file is reassembled next to `dest` and only renamed to `dest` once its
+/// size and crc have been checked, thus `dest` never holds a partial file.
+/// The metadata recorded in the digest and selected by `preserve` is restored
+/// before the rename.
+pub async fn defragment(key: &str, dest: &str, preserve: PreserveFlags) -> ZfsResult<bool> {
+    let fragments_path = zfsd_download_frags_dir_for_key(key)?;
+
+    let digest = read_defrag_digest(&fragments_path).await?;
+    let dest_path = Path::new(dest);
+    let dest_dir = dest_path
+        .parent()
+        .ok_or_else(|| ZfsError::Invalid(format!("Invalid target path: {}", dest)))?;
+    create_dir_all(dest_dir).await?;
+
+    let tmp = zfs_tmp_path(dest);
+    match reassemble(&fragments_path, &digest, &tmp).await {
+        Ok(true) => {
+            match &digest.metadata {
+                Some(meta) => restore_metadata(&tmp, meta, preserve),
+                None if !preserve.is_empty() => {
+                    log::warn!(target: "zfsd", "No metadata was recorded for {}, unable to restore it", key)
+                }
+                None => (),
             }
+            tokio::fs::rename(&tmp, dest_path).await?;
+            sync_parent_dir(dest).await;
+            Ok(true)
+        }
+        r => {
+            let _ignore = tokio::fs::remove_file(&tmp).await;
+            r
+        }
+    }
+}
 
-            drop(f);
-            let crc64 = Crc::new(dest).checksum().unwrap().crc64;
-            Ok(crc64 == digest.crc)
+async fn reassemble(fragments_path: &str, digest: &FragmentationDigest, tmp: &str) -> ZfsResult<bool> {
+    let mut f = File::create(tmp).await?;
+    for i in 0..digest.fragments {
+        if digest.is_zero_fragment(i) {
+            // Left as a hole
+            f.seek(std::io::SeekFrom::Current(digest.fragment_len(i) as i64)).await?;
+            continue;
+        }
+        let frag_path = format!("{}/{}", fragments_path, i);
+        let bs = tokio::fs::read(Path::new(&frag_path)).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                ZfsError::NotFound(format!("Missing fragment {}", frag_path))
+            } else {
+                e.into()
+            }
+        })?;
+        if bs.len() as u64 != digest.fragment_len(i) {
+            // Discarded, so that it gets downloaded again
+            let _ignore = tokio::fs::remove_file(&frag_path).await;
+            return Err(ZfsError::NotFound(format!(
+                "Fragment {} is {} bytes long instead of {}, discarded it",
+                frag_path,
+                bs.len(),
+                digest.fragment_len(i)
+            )));
         }
-        Err(e) => Err(e),
+        f.write_all(&bs).await?;
+    }
+    // The file may end with a hole
+    f.set_len(digest.size).await?;
+    f.sync_all().await?;
+    drop(f);
+
+    let size = tokio::fs::metadata(tmp).await?.len();
+    if size != digest.size {
+        return Err(ZfsError::Integrity(format!(
+            "{} is {} bytes long instead of {}",
+            &digest.name, size, digest.size
+        )));
+    }
+    let crc64 = Crc::new(tmp)
+        .checksum()
+        .map_err(|e| ZfsError::Integrity(format!("Unable to compute the checksum of {}: {}", tmp, e)))?
+        .crc64;
+    Ok(crc64 == digest.crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(target_fragments: u32, min_fragment_size: usize, max_fragment_size: usize) -> FragmentationConfig {
+        FragmentationConfig {
+            target_fragments,
+            min_fragment_size,
+            max_fragment_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn adaptive_fragment_size_clamping() {
+        let c = conf(4096, 32 * 1024, 16 * 1024 * 1024);
+        // Small files use the smallest fragments
+        assert_eq!(adaptive_fragment_size(&c, usize::MAX, 0), 32 * 1024);
+        assert_eq!(adaptive_fragment_size(&c, usize::MAX, 1024), 32 * 1024);
+        // The size aimed at is rounded up to a power of two
+        assert_eq!(adaptive_fragment_size(&c, usize::MAX, 4096 * 100 * 1024), 128 * 1024);
+        // Large files use the largest fragments
+        assert_eq!(adaptive_fragment_size(&c, usize::MAX, u64::MAX), 16 * 1024 * 1024);
+        // The largest zenoh message lowers the maximum to a power of two
+        let max_message_size = FRAGMENT_MSG_OVERHEAD + 3 * 1024 * 1024;
+        assert_eq!(adaptive_fragment_size(&c, max_message_size, u64::MAX), 2 * 1024 * 1024);
+        // Even below the minimum
+        assert_eq!(adaptive_fragment_size(&c, FRAGMENT_MSG_OVERHEAD + 1024, 0), 1024);
+        // A message too small to hold the overhead is ignored
+        assert_eq!(adaptive_fragment_size(&c, 1024, u64::MAX), 16 * 1024 * 1024);
     }
 }
@@ -0,0 +1,235 @@
+use crate::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use zenoh::qos::CongestionControl;
+use zenoh::query::{ConsolidationMode, QueryTarget};
+use zenoh::Session;
+
+///
+/// How many replicas hold a valid copy of the digest and of each stored
+/// fragment of a key.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationReport {
+    pub key: String,
+    pub digest_replicas: BTreeSet<String>,
+    pub fragment_replicas: BTreeMap<u32, BTreeSet<String>>,
+}
+
+impl ReplicationReport {
+    /// The actual replication factor of the key, i.e. the number of replicas
+    /// holding its least replicated part.
+    pub fn replication(&self) -> usize {
+        self.fragment_replicas
+            .values()
+            .map(|r| r.len())
+            .chain(std::iter::once(self.digest_replicas.len()))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Every replica holding at least a part of the key.
+    pub fn replicas(&self) -> BTreeSet<String> {
+        self.fragment_replicas
+            .values()
+            .flatten()
+            .chain(self.digest_replicas.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// The fragments held by less than `target` replicas.
+    pub fn under_replicated(&self, target: usize) -> Vec<u32> {
+        self.fragment_replicas
+            .iter()
+            .filter(|(_, r)| r.len() < target)
+            .map(|(n, _)| *n)
+            .collect()
+    }
+}
+
+/// Queries `key` on every replica, and returns the ones whose reply is
+/// accepted by `validate`.
+async fn valid_replicas(
+    z: &Session,
+    key: &str,
+    validate: impl Fn(&[u8]) -> bool,
+) -> ZfsResult<BTreeSet<String>> {
+    let replies = z
+        .get(key)
+        .target(QueryTarget::All)
+        .consolidation(ConsolidationMode::None)
+        .await?;
+    let mut replicas = BTreeSet::new();
+    while let Ok(reply) = replies.recv_async().await {
+        let replica = zfs_replica_id(&reply);
+        match (replica, reply.result()) {
+            (Some(replica), Ok(sample)) if validate(&sample.payload().to_bytes()) => {
+                replicas.insert(replica);
+            }
+            (replica, _) => log::debug!(target: "fsck", "{:?} has no valid copy of {}", replica, key),
+        }
+    }
+    Ok(replicas)
+}
+
+/// Checks which replicas hold a valid copy of the digest and of every stored
+/// fragment of `key`.
+pub async fn check_replication(
+    z: &Session,
+    key: &str,
+    digest: &FragmentationDigest,
+) -> ZfsResult<ReplicationReport> {
+    let digest_replicas = valid_replicas(z, &zfs_frags_digest_for_key(key), |bs| {
+        zfs_decode_digest(bs)
+            .is_ok_and(|d| d.generation == digest.generation && d.crc == digest.crc)
+    })
+    .await?;
+    let mut fragment_replicas = BTreeMap::new();
+    for n in digest.stored_fragments() {
+        let frag_key = zfs_frag_key(key, digest.generation.as_deref(), n);
+        let replicas = valid_replicas(z, &frag_key, |bs| digest.check_fragment(n, bs).is_ok()).await?;
+        fragment_replicas.insert(n, replicas);
+    }
+    Ok(ReplicationReport {
+        key: key.to_string(),
+        digest_replicas,
+        fragment_replicas,
+    })
+}
+
+/// Puts again the parts of `key` held by less than `target` replicas, from a
+/// valid copy, so that every storage gets them. Returns the number of parts
+/// put again.
+pub async fn repair_replication(
+    z: &Session,
+    digest: &FragmentationDigest,
+    report: &ReplicationReport,
+    target: usize,
+) -> ZfsResult<u32> {
+    let key = &report.key;
+    // Not repairing a key replaced or deleted since it was checked, that would
+    // bring back the fragments of the previous generation.
+    let current = get_fragmentation_digest(z, &zfs_frags_digest_for_key(key)).await?;
+    if current.generation != digest.generation || current.crc != digest.crc {
+        log::info!(target: "fsck", "{} changed since it was checked, not repairing it", key);
+        return Ok(0);
+    }
+    let mut repaired = 0;
+    for n in report.under_replicated(target) {
+        let frag_key = zfs_frag_key(key, digest.generation.as_deref(), n);
+        let bs = fetch_from_replicas(z, &frag_key, |bs| digest.check_fragment(n, bs)).await?;
+        log::info!(target: "fsck", "Repairing {}", &frag_key);
+        z.put(&frag_key, bs)
+            .congestion_control(CongestionControl::Block)
+            .await?;
+        repaired += 1;
+    }
+    // The digest last, as for an upload
+    if report.digest_replicas.len() < target {
+        log::info!(target: "fsck", "Repairing the digest of {}", key);
+        z.put(zfs_frags_digest_for_key(key), zfs_encode_digest(&current)?)
+            .congestion_control(CongestionControl::Block)
+            .await?;
+        repaired += 1;
+    }
+    Ok(repaired)
+}
+
+/// The replication every key should have, `target` or, when 0, the number
+/// of replicas seen in `reports`.
+fn replication_target<'a>(reports: impl Iterator<Item = &'a ReplicationReport>, target: usize) -> usize {
+    match target {
+        0 => reports.flat_map(|r| r.replicas()).collect::<BTreeSet<_>>().len(),
+        n => n,
+    }
+}
+
+/// Checks the replication of every stored key, or only of `keys` when not
+/// empty, and repairs the under-replicated ones when `repair` is set.
+///
+/// A `target` of 0 means every replica seen during the check.
+pub async fn zfs_check(
+    z: &Session,
+    keys: &[String],
+    target: usize,
+    repair: bool,
+) -> ZfsResult<Vec<ReplicationReport>> {
+    let mut reports = Vec::new();
+    for (key, digest) in list_stored_digests(z).await? {
+        if keys.is_empty() || keys.contains(&key) {
+            reports.push((check_replication(z, &key, &digest).await?, digest));
+        }
+    }
+    let target = replication_target(reports.iter().map(|(r, _)| r), target);
+    for (report, digest) in &reports {
+        let replication = report.replication();
+        if replication < target {
+            log::warn!(target: "fsck", "{} is replicated {} times instead of {}", &report.key, replication, target);
+            if repair {
+                match repair_replication(z, digest, report, target).await {
+                    Ok(n) => log::info!(target: "fsck", "Put {} parts of {} again", n, &report.key),
+                    Err(e) => log::warn!(target: "fsck", "Unable to repair {}: {}", &report.key, e),
+                }
+            }
+        }
+    }
+    Ok(reports.into_iter().map(|(r, _)| r).collect())
+}
+
+/// Periodically checks, and repairs, the replication of the stored files.
+pub async fn replication_checker(z: Arc<Session>) {
+    let conf = zfs_config();
+    loop {
+        tokio::time::sleep(conf.replicas.check_period()).await;
+        log::debug!(target: "fsck", "Checking the replication of the stored keys...");
+        if let Err(e) = zfs_check(&z, &[], conf.replicas.target, conf.replicas.repair).await {
+            log::warn!(target: "fsck", "Unable to check the replication: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(replicas: &[&str]) -> BTreeSet<String> {
+        replicas.iter().map(|r| r.to_string()).collect()
+    }
+
+    fn report(key: &str, digest: &[&str], fragments: &[&[&str]]) -> ReplicationReport {
+        ReplicationReport {
+            key: key.into(),
+            digest_replicas: set(digest),
+            fragment_replicas: fragments.iter().enumerate().map(|(n, r)| (n as u32, set(r))).collect(),
+        }
+    }
+
+    #[test]
+    fn count_replicas() {
+        let r = report("a", &["s1", "s2"], &[&["s1", "s2", "s3"], &["s2"], &["s1", "s2"]]);
+        assert_eq!(r.replication(), 1);
+        assert_eq!(r.replicas(), set(&["s1", "s2", "s3"]));
+        assert_eq!(r.under_replicated(2), vec![1]);
+        assert_eq!(r.under_replicated(3), vec![1, 2]);
+        assert!(r.under_replicated(1).is_empty());
+
+        // The digest counts as much as the fragments
+        let r = report("b", &["s1"], &[&["s1", "s2"]]);
+        assert_eq!(r.replication(), 1);
+        assert!(r.under_replicated(2).is_empty());
+        assert_eq!(report("c", &[], &[]).replication(), 0);
+        assert_eq!(report("d", &["s1", "s2"], &[]).replication(), 2);
+    }
+
+    #[test]
+    fn count_target() {
+        let reports = [
+            report("a", &["s1"], &[&["s1", "s2"]]),
+            report("b", &["s3"], &[&["s3"]]),
+        ];
+        assert_eq!(replication_target(reports.iter(), 0), 3);
+        assert_eq!(replication_target(reports.iter(), 2), 2);
+        assert_eq!(replication_target(std::iter::empty(), 0), 0);
+    }
+}
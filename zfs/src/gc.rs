@@ -0,0 +1,285 @@
+use crate::*;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The size in bytes of all the files under `path`.
+fn dir_size(path: &Path) -> u64 {
+    match path.read_dir() {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| match e.metadata() {
+                Ok(m) if m.is_dir() => dir_size(&e.path()),
+                Ok(m) => m.len(),
+                Err(_) => 0,
+            })
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+fn staging_dir(job: &Job) -> ZfsResult<String> {
+    match job.kind {
+        JobKind::Upload => zfsd_upload_frags_dir_for_key(&job.key),
+        JobKind::Download => zfsd_download_frags_dir_for_key(&job.key),
+    }
+}
+
+fn digest_path(job: &Job) -> ZfsResult<String> {
+    match job.kind {
+        JobKind::Upload => zfsd_upload_digest_path(&job.id),
+        JobKind::Download => zfsd_download_digest_path(&job.id),
+    }
+}
+
+/// Removes the staging fragments and the digest of `job`. The staging
+/// directory is shared by all the jobs on the same key, thus it is kept as
+/// long as one of them is still active.
+fn remove_staging(job: &Job, active: &HashSet<(JobKind, String)>) -> u64 {
+    let mut freed = 0;
+    if let Ok(path) = digest_path(job) {
+        let _ignore = std::fs::remove_file(path);
+    }
+    if !active.contains(&(job.kind, job.key.clone())) {
+        let Ok(dir) = staging_dir(job) else {
+            return 0;
+        };
+        let path = Path::new(&dir);
+        if path.exists() {
+            freed = dir_size(path);
+            match std::fs::remove_dir_all(path) {
+                Ok(()) => log::info!(target: "gc", "Removed staging data for {} ({} bytes)", &job.key, freed),
+                Err(e) => {
+                    log::warn!(target: "gc", "Unable to remove {}: {}", dir, e);
+                    freed = 0;
+                }
+            }
+        }
+    }
+    freed
+}
+
+/// Removes the staging directories that are not used by any known job, these
+/// are left behind by jobs that predate the job database.
+fn remove_orphans(base: &str, kind: JobKind, jobs: &[Job], ttl: u64) {
+    let known: HashSet<String> = jobs
+        .iter()
+        .filter(|j| j.kind == kind)
+        .filter_map(|j| staging_dir(j).ok())
+        .collect();
+    let mut stack = vec![std::path::PathBuf::from(base)];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = dir.read_dir() else { continue };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let p = path.to_string_lossy().to_string();
+            if known.contains(&p) || known.iter().any(|k| k.starts_with(&format!("{}/", p))) {
+                // Either a staging directory in use or one of its parents
+                if !known.contains(&p) {
+                    stack.push(path);
+                }
+                continue;
+            }
+            // Keys are nested paths, thus only directories holding a digest
+            // or fragments are staging directories.
+            if path.join(ZFS_DIGEST).exists() || path.join("0").is_file() {
+                let age = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.elapsed().ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                if age > ttl {
+                    log::info!(target: "gc", "Removing orphan staging directory {} (last modified {}s ago)", p, age);
+                    let _ignore = std::fs::remove_dir_all(&path);
+                }
+            } else {
+                stack.push(path);
+            }
+        }
+    }
+}
+
+/// Removes the staging data of the finished `job`, which is then marked as
+/// collected not to be walked again.
+fn collect_job(db: &JobDb, job: &mut Job, active: &HashSet<(JobKind, String)>) -> u64 {
+    let freed = remove_staging(job, active);
+    job.collected = true;
+    // Stored as is, not to reset its last update time
+    if let Err(e) = db.put(job) {
+        log::warn!(target: "gc", "Unable to store job {}: {}", &job.id, e);
+    }
+    freed
+}
+
+/// Runs one garbage collection cycle over the zfsd staging area.
+pub fn collect_staging(db: &JobDb) -> ZfsResult<()> {
+    let conf = &zfs_config().gc;
+    let now = zfs_now();
+    let mut jobs = db.list();
+
+    // Jobs that made no progress for too long are abandoned, not the paused
+    // ones which wait for their transfer window or for a storage
+    for job in jobs.iter_mut().filter(|j| j.is_active() && j.state != JobState::Paused) {
+        if now.saturating_sub(job.updated) > conf.job_ttl_s {
+            log::info!(target: "gc", "Job {} on {} has been inactive for more than {}s, abandoning it", &job.id, &job.key, conf.job_ttl_s);
+            // Through the job helpers for its key to be released and its
+            // hooks to run
+            if let Some(j) = zfs_update_job(&job.id, |j| {
+                if j.is_active() && j.state != JobState::Paused && now.saturating_sub(j.updated) > conf.job_ttl_s {
+                    j.state = JobState::Failed;
+                    j.error = Some("Abandoned after no progress".into());
+                }
+            }) {
+                *job = j;
+            }
+        }
+    }
+
+    let active: HashSet<(JobKind, String)> = jobs
+        .iter()
+        .filter(|j| j.is_active())
+        .map(|j| (j.kind, j.key.clone()))
+        .collect();
+
+    // Completed jobs do not need their staging data any more, once the
+    // last events have settled. Failed ones are kept for a while to allow
+    // investigating them.
+    let grace = zfs_config().gc_period().as_secs();
+    for job in jobs.iter_mut().filter(|j| {
+        !j.collected
+            && ((j.state == JobState::Completed && now.saturating_sub(j.updated) >= grace)
+                || (j.state == JobState::Failed && now.saturating_sub(j.updated) > conf.job_ttl_s))
+    }) {
+        collect_job(db, job, &active);
+    }
+
+    // The records of the jobs finished for long are forgotten
+    for job in jobs.iter().filter(|j| !j.is_active() && now.saturating_sub(j.updated) > conf.job_ttl_s) {
+        match db.remove(&job.id) {
+            Ok(()) => log::debug!(target: "gc", "Forgetting job {} on {}", &job.id, &job.key),
+            Err(e) => log::warn!(target: "gc", "Unable to remove job {}: {}", &job.id, e),
+        }
+    }
+
+    remove_orphans(&zfsd_upload_frags_dir()?, JobKind::Upload, &jobs, conf.job_ttl_s);
+    remove_orphans(&zfsd_download_frags_dir()?, JobKind::Download, &jobs, conf.job_ttl_s);
+
+    if conf.max_staging_bytes > 0 {
+        let frags_dir = format!("{}/{}", zfsd_home()?, FRAGS_SUBDIR);
+        let mut size = dir_size(Path::new(&frags_dir));
+        if size > conf.max_staging_bytes {
+            // Evict the data of the failed jobs, oldest first
+            let mut failed: Vec<&mut Job> = jobs
+                .iter_mut()
+                .filter(|j| j.state == JobState::Failed && !j.collected)
+                .collect();
+            failed.sort_by_key(|j| j.updated);
+            for job in failed {
+                if size <= conf.max_staging_bytes {
+                    break;
+                }
+                size = size.saturating_sub(collect_job(db, job, &active));
+            }
+            if size > conf.max_staging_bytes {
+                log::warn!(
+                    target: "gc",
+                    "The staging area uses {} bytes which is above the limit of {} bytes, but only active jobs are left",
+                    size,
+                    conf.max_staging_bytes
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Periodically removes the staging data that is no longer needed.
+pub async fn staging_gc() {
+    let conf = zfs_config();
+    loop {
+        tokio::time::sleep(conf.gc_period()).await;
+        match zfs_jobs() {
+            Some(db) => {
+                log::debug!(target: "gc", "Running staging GC...");
+                // Walking the staging area blocks
+                match tokio::task::spawn_blocking(move || collect_staging(db)).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => log::warn!(target: "gc", "Staging GC failed: {}", e),
+                    Err(e) => log::warn!(target: "gc", "Staging GC panicked: {}", e),
+                }
+            }
+            None => log::warn!(target: "gc", "The job database is not open, skipping staging GC"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(dir: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(format!("{}/0", dir), b"fragment").unwrap();
+    }
+
+    fn put(db: &JobDb, id: &str, kind: JobKind, state: JobState, age: u64) -> Job {
+        let mut job = Job::new(id, kind, &format!("k/{}", id), "/tmp/f");
+        job.state = state;
+        job.updated = zfs_now() - age;
+        db.put(&job).unwrap();
+        job
+    }
+
+    #[test]
+    fn collect_finished_jobs_and_orphans() {
+        let home = std::env::temp_dir().join(format!("zfs-gc-test-{}", std::process::id()));
+        let _ignore = std::fs::remove_dir_all(&home);
+        std::env::set_var("ZFSD_HOME", &home);
+        let db = JobDb::open(home.join("jobs")).unwrap();
+        let ttl = zfs_config().gc.job_ttl_s;
+
+        let completed = put(&db, "completed", JobKind::Upload, JobState::Completed, 3600);
+        let recent = put(&db, "recent", JobKind::Upload, JobState::Completed, 0);
+        let failed = put(&db, "failed", JobKind::Download, JobState::Failed, ttl + 1);
+        let running = put(&db, "running", JobKind::Download, JobState::Transferring, 0);
+        for job in [&completed, &recent, &failed, &running] {
+            stage(&staging_dir(job).unwrap());
+            std::fs::create_dir_all(Path::new(&digest_path(job).unwrap()).parent().unwrap()).unwrap();
+            std::fs::write(digest_path(job).unwrap(), b"{}").unwrap();
+        }
+        let orphan = format!("{}/old/orphan", zfsd_upload_frags_dir().unwrap());
+        let young = format!("{}/young", zfsd_upload_frags_dir().unwrap());
+        stage(&orphan);
+        stage(&young);
+        let old = filetime::FileTime::from_unix_time((zfs_now() - ttl - 10) as i64, 0);
+        filetime::set_file_mtime(&orphan, old).unwrap();
+
+        collect_staging(&db).unwrap();
+
+        let staged = |job: &Job| Path::new(&staging_dir(job).unwrap()).exists();
+        assert!(!staged(&completed) && !Path::new(&digest_path(&completed).unwrap()).exists());
+        assert!(staged(&recent) && staged(&running));
+        assert!(!staged(&failed));
+        assert!(!Path::new(&orphan).exists());
+        assert!(Path::new(&young).exists());
+
+        let stored = db.get("completed").unwrap().unwrap();
+        assert!(stored.collected);
+        assert_eq!(stored.updated, completed.updated);
+        // Finished for longer than the ttl, thus forgotten
+        assert!(db.get("failed").unwrap().is_none());
+        assert!(!db.get("running").unwrap().unwrap().collected);
+
+        // A collected job is not walked again, even if its key is staged anew
+        stage(&staging_dir(&completed).unwrap());
+        collect_staging(&db).unwrap();
+        assert!(staged(&completed));
+
+        drop(db);
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
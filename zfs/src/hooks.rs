@@ -0,0 +1,264 @@
+use crate::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use zenoh::key_expr::KeyExpr;
+
+//
+// Hooks are run once a job completes, i.e. once an upload is committed or a
+// downloaded file is in place with the expected crc, or fails. A hook either:
+//
+//  - runs a shell command, with the job in its environment: ZFS_JOB,
+//    ZFS_KIND (upload or download), ZFS_STATE (completed or failed), ZFS_KEY,
+//    ZFS_PATH, ZFS_SIZE and ZFS_ERROR;
+//  - or POSTs the job, as shown by `zst`, in json to a local HTTP endpoint,
+//    e.g. `http://127.0.0.1:8080/zfs`.
+//
+// The hooks of a job come from `zut --hook` and `zet --hook`, followed by the
+// configured ones whose prefix includes the key. Their failures are only
+// logged.
+//
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    Completed,
+    Failed,
+}
+
+fn all_hook_events() -> Vec<HookEvent> {
+    vec![HookEvent::Completed, HookEvent::Failed]
+}
+
+///
+/// A command to run, or an HTTP endpoint to notify, when a job completes or
+/// fails.
+///
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Run with `sh -c`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Only `http://` to `localhost` or a loopback address is supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The outcomes of the job the hook runs on, both by default.
+    #[serde(default = "all_hook_events")]
+    pub on: Vec<HookEvent>,
+}
+
+impl Hook {
+    pub fn validate(&self) -> ZfsResult<()> {
+        match (&self.command, &self.url) {
+            (Some(_), None) => Ok(()),
+            (None, Some(url)) => parse_url(url).map(|_| ()),
+            _ => Err(ZfsError::Invalid("A hook has either a command or a url".into())),
+        }
+    }
+}
+
+impl std::str::FromStr for Hook {
+    type Err = ZfsError;
+
+    /// Parses an `http://` url, or else a command, run on both outcomes.
+    fn from_str(s: &str) -> ZfsResult<Hook> {
+        let (command, url) = match s.starts_with("http://") {
+            true => (None, Some(s.to_string())),
+            false => (Some(s.to_string()), None),
+        };
+        let hook = Hook { command, url, on: all_hook_events() };
+        hook.validate()?;
+        Ok(hook)
+    }
+}
+
+/// Splits an `http://host:port/path` url into the address and the path. The
+/// host has to be `localhost` or a loopback address, the jobs are not sent
+/// over the network.
+fn parse_url(url: &str) -> ZfsResult<(String, String)> {
+    let invalid = || ZfsError::Invalid(format!("Invalid hook url: {}", url));
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| ZfsError::Invalid(format!("Only http:// hooks are supported: {}", url)))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (name, port) = match host.strip_prefix('[') {
+        Some(v6) => match v6.split_once(']').ok_or_else(invalid)? {
+            (name, "") => (name, None),
+            (name, port) => (name, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+        },
+        None => match host.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+        None => 80,
+    };
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    if name != "localhost" && !name.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback()) {
+        return Err(ZfsError::Invalid(format!("Only local hook urls are supported: {}", url)));
+    }
+    let addr = match name.contains(':') {
+        true => format!("[{}]:{}", name, port),
+        false => format!("{}:{}", name, port),
+    };
+    Ok((addr, path.to_string()))
+}
+
+/// The hooks to run for `job`, now that it is in its final state.
+fn hooks_for(job: &Job) -> Vec<Hook> {
+    let event = match job.state {
+        JobState::Completed => HookEvent::Completed,
+        JobState::Failed => HookEvent::Failed,
+        _ => return vec![],
+    };
+    let zkey = KeyExpr::try_from(zfs_key(&job.key)).ok();
+    let configured = zfs_config().hooks.iter().filter(|h| {
+        (h.kinds.is_empty() || h.kinds.contains(&job.kind))
+            && zkey
+                .as_ref()
+                .is_some_and(|k| KeyExpr::try_from(h.prefix.as_str()).is_ok_and(|ke| ke.includes(k)))
+    });
+    job.hooks
+        .iter()
+        .chain(configured.map(|h| &h.hook))
+        .filter(|h| h.on.contains(&event))
+        .cloned()
+        .collect()
+}
+
+/// Runs `command` for `job`, it is killed unless it exits within
+/// `HOOK_TIMEOUT`.
+async fn run_command(job: &Job, command: &str) -> ZfsResult<()> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("ZFS_JOB", &job.id)
+        .env("ZFS_KIND", format!("{:?}", job.kind).to_lowercase())
+        .env("ZFS_STATE", format!("{:?}", job.state).to_lowercase())
+        .env("ZFS_KEY", &job.key)
+        .env("ZFS_PATH", &job.path)
+        .env("ZFS_SIZE", job.size.to_string())
+        .env("ZFS_ERROR", job.error.as_deref().unwrap_or_default())
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let status = match tokio::time::timeout(HOOK_TIMEOUT, child.wait()).await {
+        Ok(status) => status?,
+        Err(_) => {
+            let _ignore = child.kill().await;
+            return Err(ZfsError::Timeout(format!("{} did not exit within {:?}", command, HOOK_TIMEOUT)));
+        }
+    };
+    if !status.success() {
+        return Err(ZfsError::Invalid(format!("{} exited with {}", command, status)));
+    }
+    Ok(())
+}
+
+async fn post(job: &Job, url: &str) -> ZfsResult<()> {
+    let (addr, path) = parse_url(url)?;
+    let body = serde_json::to_vec(job)?;
+    let request = async {
+        let mut stream = tokio::net::TcpStream::connect(&addr).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            addr,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        let mut status = [0u8; 12];
+        stream.read_exact(&mut status).await?;
+        Ok::<_, std::io::Error>(String::from_utf8_lossy(&status[9..]).to_string())
+    };
+    let status = tokio::time::timeout(HOOK_TIMEOUT, request)
+        .await
+        .map_err(|_| ZfsError::Timeout(format!("No reply from {}", url)))??;
+    if !status.starts_with('2') {
+        return Err(ZfsError::Invalid(format!("{} replied with status {}", url, status)));
+    }
+    Ok(())
+}
+
+/// Runs the hooks of `job`, which just completed or failed, in the background.
+pub(crate) fn zfs_run_hooks(job: &Job) {
+    let hooks = hooks_for(job);
+    if hooks.is_empty() {
+        return;
+    }
+    let Ok(rt) = tokio::runtime::Handle::try_current() else {
+        log::warn!(target: "hooks", "Unable to run the hooks of job {} outside of a runtime", &job.id);
+        return;
+    };
+    let job = job.clone();
+    rt.spawn(async move {
+        for hook in hooks {
+            let r = match (&hook.command, &hook.url) {
+                (Some(command), _) => run_command(&job, command).await,
+                (None, Some(url)) => post(&job, url).await,
+                (None, None) => Ok(()),
+            };
+            match r {
+                Ok(()) => log::debug!(target: "hooks", "Ran hook {:?} for job {}", &hook, &job.id),
+                Err(e) => log::warn!(target: "hooks", "Hook {:?} failed for job {} on {}: {}", &hook, &job.id, &job.key, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(url: &str) -> (String, String) {
+        parse_url(url).unwrap()
+    }
+
+    #[test]
+    fn parse_local_urls() {
+        assert_eq!(parsed("http://127.0.0.1:8080/zfs"), ("127.0.0.1:8080".into(), "/zfs".into()));
+        assert_eq!(parsed("http://localhost"), ("localhost:80".into(), "/".into()));
+        assert_eq!(parsed("http://127.1.2.3/a/b?c=d"), ("127.1.2.3:80".into(), "/a/b?c=d".into()));
+        assert_eq!(parsed("http://[::1]:9000/zfs"), ("[::1]:9000".into(), "/zfs".into()));
+        assert_eq!(parsed("http://[::1]"), ("[::1]:80".into(), "/".into()));
+    }
+
+    #[test]
+    fn parse_invalid_urls() {
+        for url in [
+            "https://127.0.0.1/zfs",
+            "127.0.0.1:8080/zfs",
+            "http://",
+            "http:///zfs",
+            "http://:8080/zfs",
+            "http://127.0.0.1:port/zfs",
+            "http://127.0.0.1:65536/zfs",
+            "http://[::1/zfs",
+            "http://[::1]8080/zfs",
+        ] {
+            assert!(matches!(parse_url(url), Err(ZfsError::Invalid(_))), "{}", url);
+        }
+    }
+
+    #[test]
+    fn parse_remote_urls() {
+        for url in [
+            "http://example.com/zfs",
+            "http://10.0.0.1:8080/zfs",
+            "http://0.0.0.0/zfs",
+            "http://[2001:db8::1]/zfs",
+            "http://localhost.example.com/zfs",
+            "http://::1/zfs",
+        ] {
+            assert!(parse_url(url).is_err(), "{}", url);
+        }
+        assert!("http://example.com/zfs".parse::<Hook>().is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use crate::*;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zenoh::Session;
+
+static JOBS: OnceLock<JobDb> = OnceLock::new();
+
+pub const JOBS_DB_SUBDIR: &str = "jobs";
+pub const ZFSD_STATUS_PREFIX: &str = "@zfsd";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    #[serde(alias = "upload")]
+    Upload,
+    #[serde(alias = "download")]
+    Download,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Pending,
+    Fragmenting,
+    Transferring,
+    /// All the fragments are stored, the digest is being put.
+    Committing,
+    /// Waiting for the transfer window of the job to open.
+    Paused,
+    Completed,
+    Failed,
+}
+
+///
+/// The state kept by the download sanitizer for a job that has gaps.
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SanitizerState {
+    pub tide_level: usize,
+    pub gap_num: usize,
+    pub stuck_cycles: usize,
+}
+
+///
+/// A zfsd job. The id is the name of the upload/download digest that
+/// created the job.
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    pub key: String,
+    pub path: String,
+    pub state: JobState,
+    pub error: Option<String>,
+    pub size: u64,
+    pub fragments: u32,
+    pub transferred: u32,
+    pub sanitizer: Option<SanitizerState>,
+    /// The trusted signer of the file, if signed.
+    #[serde(default)]
+    pub signer: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<TransferWindow>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+    /// True once the staging data of the finished job has been removed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub collected: bool,
+    /// Seconds since the UNIX epoch.
+    pub created: u64,
+    pub updated: u64,
+}
+
+impl Job {
+    pub fn new(id: &str, kind: JobKind, key: &str, path: &str) -> Self {
+        let now = zfs_now();
+        Job {
+            id: id.into(),
+            kind,
+            key: key.into(),
+            path: path.into(),
+            state: JobState::Pending,
+            error: None,
+            size: 0,
+            fragments: 0,
+            transferred: 0,
+            sanitizer: None,
+            signer: None,
+            priority: Priority::Normal,
+            windows: Vec::new(),
+            hooks: Vec::new(),
+            collected: false,
+            created: now,
+            updated: now,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, JobState::Completed | JobState::Failed)
+    }
+}
+
+pub fn zfs_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+///
+/// The persistent job database of zfsd, stored under `~/.zfsd/jobs`.
+///
+pub struct JobDb {
+    db: sled::Db,
+}
+
+impl JobDb {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> ZfsResult<Self> {
+        Ok(JobDb {
+            db: sled::open(path)?,
+        })
+    }
+
+    pub fn get(&self, id: &str) -> ZfsResult<Option<Job>> {
+        match self.db.get(id)? {
+            Some(bs) => Ok(Some(serde_json::from_slice(&bs)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `job`, and returns the job it replaces, if any.
+    pub fn put(&self, job: &Job) -> ZfsResult<Option<Job>> {
+        let previous = self.db.insert(&job.id, serde_json::to_vec(job)?)?;
+        Ok(previous.and_then(|bs| serde_json::from_slice(&bs).ok()))
+    }
+
+    pub fn remove(&self, id: &str) -> ZfsResult<()> {
+        self.db.remove(id)?;
+        Ok(())
+    }
+
+    /// Atomically applies `f` to the job `id`, returns the updated job or
+    /// `None` if there is no such job.
+    pub fn update<F: Fn(&mut Job)>(&self, id: &str, f: F) -> ZfsResult<Option<Job>> {
+        let mut codec_err = None;
+        let r = self.db.update_and_fetch(id, |old| {
+            let bs = old?;
+            match serde_json::from_slice::<Job>(bs) {
+                Ok(mut job) => {
+                    f(&mut job);
+                    job.updated = zfs_now();
+                    serde_json::to_vec(&job).ok()
+                }
+                Err(e) => {
+                    codec_err = Some(e);
+                    Some(bs.to_vec())
+                }
+            }
+        })?;
+        if let Some(e) = codec_err {
+            return Err(e.into());
+        }
+        match r {
+            Some(bs) => Ok(Some(serde_json::from_slice(&bs)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.db
+            .iter()
+            .values()
+            .flatten()
+            .filter_map(|bs| serde_json::from_slice(&bs).ok())
+            .collect()
+    }
+
+    /// The active job of the given kind for `key`, if any.
+    pub fn find_active(&self, kind: JobKind, key: &str) -> Option<Job> {
+        self.list()
+            .into_iter()
+            .find(|j| j.kind == kind && j.key == key && j.is_active())
+    }
+
+    /// The job of the given kind running on `key`, i.e. active and not
+    /// waiting for its turn, if any.
+    pub fn find_running(&self, kind: JobKind, key: &str) -> Option<Job> {
+        self.list()
+            .into_iter()
+            .find(|j| j.kind == kind && j.key == key && j.is_active() && j.state != JobState::Pending)
+    }
+
+    pub fn flush(&self) -> ZfsResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+pub fn zfsd_jobs_db_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_home()?, JOBS_DB_SUBDIR))
+}
+
+/// Opens the job database of this process, it should be called once by zfsd
+/// before it starts any job.
+pub fn zfs_open_jobs() -> ZfsResult<()> {
+    let db = JobDb::open(zfsd_jobs_db_dir()?)?;
+    JOBS.set(db)
+        .map_err(|_| ZfsError::Config("The job database has already been opened".into()))
+}
+
+/// The job database of this process, `None` when running outside of zfsd.
+pub fn zfs_jobs() -> Option<&'static JobDb> {
+    JOBS.get()
+}
+
+/// Updates the job `id` if the job database is open, failures are only logged
+/// as they should never stop a transfer. The key held by the job is released
+/// once it completes or fails.
+pub(crate) fn zfs_update_job<F: Fn(&mut Job)>(id: &str, f: F) -> Option<Job> {
+    // The hooks only run once, when the job completes or fails
+    let was_active = std::cell::Cell::new(false);
+    let f = |j: &mut Job| {
+        was_active.set(j.is_active());
+        f(j)
+    };
+    match zfs_jobs().map(|db| db.update(id, f)) {
+        Some(Ok(job)) => {
+            if let Some(j) = job.as_ref().filter(|j| !j.is_active()) {
+                zfs_release_key(id);
+                zfs_forget_limiters(id);
+                if was_active.get() {
+                    zfs_run_hooks(j);
+                }
+            }
+            job
+        }
+        Some(Err(e)) => {
+            log::warn!(target: "jobs", "Unable to update job {}: {}", id, e);
+            None
+        }
+        None => None,
+    }
+}
+
+pub(crate) fn zfs_put_job(job: &Job) {
+    // The hooks only run once, when the job completes or fails
+    let was_active = match zfs_jobs().map(|db| db.put(job)) {
+        Some(Ok(previous)) => previous.is_none_or(|p| p.is_active()),
+        Some(Err(e)) => {
+            log::warn!(target: "jobs", "Unable to store job {}: {}", &job.id, e);
+            true
+        }
+        None => true,
+    };
+    if !job.is_active() {
+        zfs_release_key(&job.id);
+        zfs_forget_limiters(&job.id);
+        if was_active {
+            zfs_run_hooks(job);
+        }
+    }
+}
+
+/// The id of the job created by the digest stored at `path`.
+pub fn zfs_job_id(path: &std::path::Path) -> String {
+    path.file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+pub fn zfsd_status_key(zid: &str) -> String {
+    format!("{}/{}/jobs", ZFSD_STATUS_PREFIX, zid)
+}
+
+/// Serves the content of the job database on `@zfsd/<zid>/jobs/<id>`, so
+/// that clients can query the status and history of the jobs.
+pub async fn serve_job_status(z: std::sync::Arc<Session>) -> ZfsResult<()> {
+    let prefix = zfsd_status_key(&z.zid().to_string());
+    let queryable = z.declare_queryable(format!("{}/**", prefix)).await?;
+    while let Ok(query) = queryable.recv_async().await {
+        let jobs = zfs_jobs().map(|db| db.list()).unwrap_or_default();
+        for job in jobs {
+            let key = format!("{}/{}", prefix, job.id);
+            // One job whose id is not a valid key chunk must not stop the others
+            match zenoh::key_expr::KeyExpr::try_from(key.as_str()) {
+                Ok(k) if query.key_expr().intersects(&k) => (),
+                Ok(_) => continue,
+                Err(e) => {
+                    log::debug!(target: "jobs", "Not serving the status of job {}: {}", &job.id, e);
+                    continue;
+                }
+            }
+            let r = match serde_json::to_vec(&job) {
+                Ok(bs) => query.reply(&key, bs).await.map_err(ZfsError::from),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = r {
+                log::warn!(target: "jobs", "Unable to reply to status query: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_db_persistence() {
+        let path = std::env::temp_dir().join(format!("zfs-jobs-test-{}", std::process::id()));
+        let _ignore = std::fs::remove_dir_all(&path);
+        {
+            let db = JobDb::open(&path).unwrap();
+            let mut job = Job::new("a", JobKind::Upload, "k/a", "/tmp/a");
+            job.windows = vec!["22:00-06:00".parse().unwrap()];
+            db.put(&job).unwrap();
+            db.put(&Job::new("b", JobKind::Download, "k/b", "/tmp/b")).unwrap();
+            db.put(&Job::new("c", JobKind::Download, "k/c", "/tmp/c")).unwrap();
+            let mut replaced = Job::new("c", JobKind::Download, "k/c", "/tmp/c");
+            replaced.state = JobState::Completed;
+            let previous = db.put(&replaced).unwrap().unwrap();
+            assert_eq!((previous.id.as_str(), previous.state), ("c", JobState::Pending));
+            assert!(previous.is_active() && !db.put(&replaced).unwrap().unwrap().is_active());
+
+            let updated = db.update("a", |j| j.state = JobState::Transferring).unwrap().unwrap();
+            assert_eq!(updated.state, JobState::Transferring);
+            assert!(db.update("missing", |j| j.state = JobState::Failed).unwrap().is_none());
+            db.update("b", |j| j.state = JobState::Completed).unwrap();
+            db.remove("c").unwrap();
+
+            assert!(db.find_running(JobKind::Upload, "k/a").is_some());
+            assert!(db.find_active(JobKind::Download, "k/b").is_none());
+            db.flush().unwrap();
+        }
+        // sled releases its lock once its background threads are done
+        let db = (0..50)
+            .find_map(|_| {
+                JobDb::open(&path)
+                    .map_err(|_| std::thread::sleep(std::time::Duration::from_millis(100)))
+                    .ok()
+            })
+            .unwrap();
+        let mut ids: Vec<String> = db.list().into_iter().map(|j| j.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b"]);
+        let a = db.get("a").unwrap().unwrap();
+        assert_eq!((a.kind, a.state, a.key.as_str()), (JobKind::Upload, JobState::Transferring, "k/a"));
+        assert_eq!(a.windows.len(), 1);
+        assert_eq!(db.get("b").unwrap().unwrap().state, JobState::Completed);
+        assert!(db.get("c").unwrap().is_none());
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
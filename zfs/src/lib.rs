@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
 use std::time::Duration;
 
 pub const FS_EVT_DELAY: u64 = 1;
@@ -7,19 +6,47 @@ pub const SANITIZER_PERIOD: Duration = Duration::from_secs(3);
 pub const GAP_DOWNLOAD_SCHEDULE: usize = 32;
 pub const STUCK_CYCLES_RESET: usize = 3;
 pub const MAX_ACCELERATION: usize = 33;
+pub const MAX_JOBS: usize = 16;
+pub const MAX_INFLIGHT_FRAGMENTS: usize = 64;
+pub const GC_PERIOD: Duration = Duration::from_secs(60);
+pub const STAGING_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+pub const RETENTION_PERIOD: Duration = Duration::from_secs(3600);
+pub const DOWN_RANK_AFTER: u32 = 3;
+pub const DOWN_RANK_PERIOD: Duration = Duration::from_secs(300);
+pub const REPLICATION_CHECK_PERIOD: Duration = Duration::from_secs(24 * 3600);
+pub const SWARM_TIMEOUT: Duration = Duration::from_secs(2);
+pub const UPLOAD_RETRIES: u32 = 3;
+pub const UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+pub const CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+pub const SHM_POOL_SIZE: usize = 64 * 1024 * 1024;
+pub const FOLLOW_CATCH_UP_PERIOD: Duration = Duration::from_secs(600);
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const SCRUB_PERIOD: Duration = Duration::from_secs(7 * 24 * 3600);
+pub const SCRUB_MAX_BPS: u64 = 100_000_000;
 
 pub const ZFS_BASE_DIR: &str = "zfs";
 pub const ZFS_DIGEST: &str = "zfs-digest";
 pub const DOWNLOAD_SUBDIR: &str = "download";
 pub const UPLOAD_SUBDIR: &str = "upload";
+pub const FOLLOW_SUBDIR: &str = "follow";
 pub const FRAGS_SUBDIR: &str = "frags";
 pub const DIGEST_SUBDIR: &str = "digest";
+pub const ZFS_TMP_SUFFIX: &str = ".zfs-tmp";
 pub const FRAGMENT_SIZE: usize = 32 * 1024;
+pub const MIN_FRAGMENT_SIZE: usize = 32 * 1024;
+pub const MAX_FRAGMENT_SIZE: usize = 16 * 1024 * 1024;
+pub const TARGET_FRAGMENTS: u32 = 4096;
+/// The room left for the key and the headers of a fragment in a zenoh message.
+pub const FRAGMENT_MSG_OVERHEAD: usize = 64 * 1024;
 
 ///
 /// The ZFS structure is as follows:
 ///
 /// .zfsd
+///   +- jobs
+///   |
+///   +- cache
+///   |
 ///   +- digest
 ///   |    +- download
 ///   |    +- upload
@@ -34,13 +61,18 @@ pub const FRAGMENT_SIZE: usize = 32 * 1024;
 ///      +- some
 ///           +- key
 ///                +- zfs-digest
-///                +- 0
-///                +- 1
-///                +- ..
-///                +- n
+///                +- <generation>
+///                      +- 0
+///                      +- 1
+///                      +- ..
+///                      +- n
 ///
+/// The fragments of each upload are stored under a new generation, and the
+/// digest is only put once all of them are stored. Thus readers never see a
+/// partially uploaded file.
 ///
-/// Where zfs is just the top level directory under the Zenoh File System backend.
+/// Where zfs is just the top level directory under the Zenoh File System backend,
+/// it can be changed with the `storage.prefix` entry of the zfsd configuration.
 ///
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FragmentationDigest {
@@ -49,13 +81,126 @@ pub struct FragmentationDigest {
     pub crc: u64,
     pub fragment_size: usize,
     pub fragments: u32,
+    /// When the file expires, in seconds since the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// Stored next to the digest, see `zfs_encode_digest`.
+    #[serde(skip)]
+    pub signature: Option<DigestSignature>,
+    /// The generation under which the fragments are stored, `None` for files
+    /// stored before generations were introduced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<String>,
+    /// The metadata recorded on upload, if asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+    /// The runs of fragments holding only zeros, as `(first, count)`. They
+    /// are neither stored nor transferred, and are recreated as holes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zero_fragments: Vec<(u32, u32)>,
+    /// True when `fragment_size` was picked by zfsd from the size of the file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub adaptive_fragment_size: bool,
+    /// The hex encoded sha256 of every fragment, empty for the zero fragments.
+    /// Missing from the digests of the files uploaded before they were added.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fragment_hashes: Vec<String>,
+}
+
+impl FragmentationDigest {
+    /// The expected length of the fragment `n`, only the last fragment may be
+    /// shorter than `fragment_size`.
+    pub fn fragment_len(&self, n: u32) -> u64 {
+        let offset = n as u64 * self.fragment_size as u64;
+        std::cmp::min(self.fragment_size as u64, self.size.saturating_sub(offset))
+    }
+
+    pub fn is_zero_fragment(&self, n: u32) -> bool {
+        self.zero_fragments
+            .iter()
+            .any(|(first, count)| n >= *first && n - first < *count)
+    }
+
+    /// The fragments that are actually stored, i.e. not made of zeros only.
+    pub fn stored_fragments(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.fragments).filter(|n| !self.is_zero_fragment(*n))
+    }
+
+    /// Checks `bs` against the length of the fragment `n` and, when recorded,
+    /// against its hash. The digests either record the hash of every
+    /// fragment, or none for the files uploaded before they were added.
+    pub fn check_fragment(&self, n: u32, bs: &[u8]) -> ZfsResult<()> {
+        let len = self.fragment_len(n);
+        if bs.len() as u64 != len {
+            return Err(ZfsError::Integrity(format!(
+                "Fragment {} of {} is {} bytes long instead of {}",
+                n,
+                &self.name,
+                bs.len(),
+                len
+            )));
+        }
+        if self.fragment_hashes.is_empty() {
+            return Ok(());
+        }
+        if self.fragment_hashes.len() != self.fragments as usize {
+            return Err(ZfsError::Integrity(format!(
+                "The digest of {} has {} fragment hashes for {} fragments",
+                &self.name,
+                self.fragment_hashes.len(),
+                self.fragments
+            )));
+        }
+        if self.fragment_hashes.get(n as usize) != Some(&zfs_fragment_hash(bs)) {
+            return Err(ZfsError::Integrity(format!(
+                "Fragment {} of {} does not match its hash",
+                n, &self.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// The number of fragments that are actually stored, fails when the zero
+    /// fragments runs do not fit in `fragments`.
+    pub fn stored_fragment_count(&self) -> ZfsResult<u32> {
+        self.zero_fragments
+            .iter()
+            .try_fold(0u32, |sum, (_, count)| sum.checked_add(*count))
+            .and_then(|zeros| self.fragments.checked_sub(zeros))
+            .ok_or_else(|| {
+                ZfsError::Integrity(format!(
+                    "The zero fragments of {} exceed its {} fragments",
+                    &self.name, self.fragments
+                ))
+            })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadDigest {
     pub path: String,
     pub key: String,
+    /// 0 means that zfsd will use its configured fragment size.
+    #[serde(default)]
     pub fragment_size: usize,
+    /// Asks zfsd to pick the fragment size from the size of the file, when
+    /// `fragment_size` is 0.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub adaptive_fragment_size: bool,
+    /// When the file expires, in seconds since the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// The metadata to record in the fragmentation digest.
+    #[serde(default, skip_serializing_if = "PreserveFlags::is_empty")]
+    pub preserve: PreserveFlags,
+    #[serde(default)]
+    pub priority: Priority,
+    /// The windows of the day during which the file is uploaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<TransferWindow>,
+    /// Run once the upload completes or fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -63,57 +208,139 @@ pub struct DownloadDigest {
     pub key: String,
     pub path: String,
     pub pace: usize,
+    /// The recorded metadata to restore on the downloaded file.
+    #[serde(default, skip_serializing_if = "PreserveFlags::is_empty")]
+    pub preserve: PreserveFlags,
+    #[serde(default)]
+    pub priority: Priority,
+    /// The windows of the day during which the file is downloaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<TransferWindow>,
+    /// Run once the download completes or fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+    /// Replaces the file at `path` once the new one is downloaded, rather
+    /// than taking an existing file as already downloaded.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replace: bool,
 }
 
-#[derive(Debug)]
-struct SanitizerRegistryEntry {
-    digest: std::sync::Arc<DownloadDigest>,
-    tide_level: usize,
-    gap_nun: usize,
-    stuck_cycles: usize,
+///
+/// A standing subscription: every file committed under `key_expr` is
+/// downloaded into the same relative path under the `path` directory.
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FollowDigest {
+    pub key_expr: String,
+    pub path: String,
+    /// The recorded metadata to restore on the downloaded files.
+    #[serde(default, skip_serializing_if = "PreserveFlags::is_empty")]
+    pub preserve: PreserveFlags,
+    #[serde(default)]
+    pub priority: Priority,
+    /// The windows of the day during which the files are downloaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<TransferWindow>,
+    /// Run once the download of each file completes or fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
 }
 
+mod availability;
+mod cache;
+mod config;
+mod coordinator;
+mod error;
+mod events;
+mod follow;
+mod hooks;
 mod frag;
+mod fsck;
+mod gc;
+mod jobs;
+mod meta;
+mod publish;
+mod quota;
+mod replicas;
+mod retention;
 mod sanitizer;
+mod scheduler;
+mod scrub;
+mod shm;
+mod signature;
+mod swarm;
 mod transfer;
+mod window;
 
+pub use availability::*;
+pub use cache::*;
+pub use config::*;
+pub use coordinator::*;
+pub use error::{ZfsError, ZfsResult};
+pub use events::*;
+pub use follow::*;
+pub use hooks::*;
 pub use frag::*;
+pub use fsck::*;
+pub use gc::staging_gc;
+pub use jobs::*;
+pub use meta::*;
+pub use publish::*;
+pub use quota::check_quota;
+pub use replicas::*;
+pub use retention::*;
 pub use sanitizer::{download_sanitizer, upload_sanitizer};
+pub use scheduler::*;
+pub use scrub::*;
+pub use shm::*;
+pub use signature::*;
+pub use swarm::*;
 pub use transfer::*;
+pub use window::*;
 
-pub fn zfs_err2str<E: Debug>(e: E) -> String {
-    format!("{:?}", e)
-}
-
-pub fn zfsd_home() -> String {
+/// The zfsd directory, `$ZFSD_HOME` or else `~/.zfsd`.
+pub fn zfsd_home() -> ZfsResult<String> {
     if let Ok(path) = std::env::var("ZFSD_HOME") {
-        path
-    } else {
-        format!("{}/{}", std::env::var("HOME").unwrap(), ".zfsd")
+        return Ok(path);
     }
+    std::env::var("HOME")
+        .map(|home| format!("{}/{}", home, ".zfsd"))
+        .map_err(|_| ZfsError::Config("Neither ZFSD_HOME nor HOME is set".into()))
 }
 
 // ZFS key-related functions
+pub fn zfs_base_dir() -> &'static str {
+    &zfs_config().storage.prefix
+}
 pub fn zfs_key(key: &str) -> String {
-    format!("{}/{}", ZFS_BASE_DIR, key)
+    format!("{}/{}", zfs_base_dir(), key)
 }
 pub fn zfs_frags_digest_for_key(key: &str) -> String {
-    format!("{}/{}/{}", ZFS_BASE_DIR, key, ZFS_DIGEST)
+    format!("{}/{}/{}", zfs_base_dir(), key, ZFS_DIGEST)
 }
 pub fn zfs_nth_frag_key(key: &str, n: u32) -> String {
-    format!("{}/{}/{}", ZFS_BASE_DIR, key, n)
+    format!("{}/{}/{}", zfs_base_dir(), key, n)
+}
+pub fn zfs_frag_key(key: &str, generation: Option<&str>, n: u32) -> String {
+    match generation {
+        Some(g) => format!("{}/{}/{}/{}", zfs_base_dir(), key, g, n),
+        None => zfs_nth_frag_key(key, n),
+    }
 }
 
 // ZFSD path-related functions
-pub fn zfsd_upload_digest_dir() -> String {
-    format!("{}/{}/{}", zfsd_home(), DIGEST_SUBDIR, UPLOAD_SUBDIR)
+pub fn zfsd_upload_digest_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}/{}", zfsd_home()?, DIGEST_SUBDIR, UPLOAD_SUBDIR))
+}
+pub fn zfsd_download_digest_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}/{}", zfsd_home()?, DIGEST_SUBDIR, DOWNLOAD_SUBDIR))
 }
-pub fn zfsd_download_digest_dir() -> String {
-    format!("{}/{}/{}", zfsd_home(), DIGEST_SUBDIR, DOWNLOAD_SUBDIR)
+pub fn zfsd_follow_digest_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}/{}", zfsd_home()?, DIGEST_SUBDIR, FOLLOW_SUBDIR))
 }
 
-pub fn zfsd_upload_frags_dir() -> String {
-    format!("{}/{}/{}", zfsd_home(), FRAGS_SUBDIR, UPLOAD_SUBDIR)
+pub fn zfsd_upload_frags_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}/{}", zfsd_home()?, FRAGS_SUBDIR, UPLOAD_SUBDIR))
 }
 // These shouldn't be needed any more:
 
@@ -126,29 +353,123 @@ pub fn zfsd_upload_frags_dir() -> String {
 
 
 
-pub fn zfsd_download_frags_dir() -> String {
-    format!("{}/{}/{}", zfsd_home(), FRAGS_SUBDIR, DOWNLOAD_SUBDIR)
+pub fn zfsd_download_frags_dir() -> ZfsResult<String> {
+    Ok(format!("{}/{}/{}", zfsd_home()?, FRAGS_SUBDIR, DOWNLOAD_SUBDIR))
 }
 
-pub fn zfsd_download_frags_dir_for_key(k: &str) -> String {
-    format!("{}/{}", zfsd_download_frags_dir(), k)
+pub fn zfsd_upload_digest_path(job_id: &str) -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_upload_digest_dir()?, job_id))
 }
 
+pub fn zfsd_download_digest_path(job_id: &str) -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_download_digest_dir()?, job_id))
+}
+
+pub fn zfsd_download_frags_dir_for_key(k: &str) -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_download_frags_dir()?, k))
+}
 
-pub fn zfsd_upload_frags_dir_for_key(k: &str) -> String {
-    format!("{}/{}", zfsd_upload_frags_dir(), k)
+
+pub fn zfsd_upload_frags_dir_for_key(k: &str) -> ZfsResult<String> {
+    Ok(format!("{}/{}", zfsd_upload_frags_dir()?, k))
 }
 
 pub fn zfsd_upload_frag_dir_to_key(path: &str) -> Option<String> {
-    path.strip_prefix(&zfsd_upload_frags_dir())
+    path.strip_prefix(&zfsd_upload_frags_dir().ok()?)
         .map(|s| s[1..].to_string()) // skip the initial "/"
 }
 
+/// A temporary path, unique to this write, next to `path`. Files are written
+/// there first and then renamed to `path`.
+pub fn zfs_tmp_path(path: &str) -> String {
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("{}.{}-{}{}", path, std::process::id(), n, ZFS_TMP_SUFFIX)
+}
+
+pub fn zfs_is_tmp_path(path: &str) -> bool {
+    path.ends_with(ZFS_TMP_SUFFIX)
+}
+
 pub async fn zfs_read_download_digest_from(
     path: &std::path::Path,
-) -> Result<DownloadDigest, String> {
-    tokio::fs::read(path)
-        .await
-        .map_err(zfs_err2str)
-        .and_then(|bs| serde_json::from_slice::<crate::DownloadDigest>(&bs).map_err(zfs_err2str))
+) -> ZfsResult<DownloadDigest> {
+    let bs = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice::<crate::DownloadDigest>(&bs)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(size: u64, fragment_size: usize, zero_fragments: Vec<(u32, u32)>) -> FragmentationDigest {
+        FragmentationDigest {
+            name: "test".into(),
+            size,
+            crc: 0,
+            fragment_size,
+            fragments: size.div_ceil(fragment_size as u64) as u32,
+            expires: None,
+            signature: None,
+            generation: None,
+            metadata: None,
+            zero_fragments,
+            adaptive_fragment_size: false,
+            fragment_hashes: vec![],
+        }
+    }
+
+    #[test]
+    fn fragment_len() {
+        let d = digest(10, 4, vec![]);
+        assert_eq!(d.fragments, 3);
+        assert_eq!(d.fragment_len(0), 4);
+        assert_eq!(d.fragment_len(1), 4);
+        assert_eq!(d.fragment_len(2), 2);
+        assert_eq!(d.fragment_len(3), 0);
+        assert_eq!(digest(8, 4, vec![]).fragment_len(1), 4);
+        assert_eq!(digest(0, 4, vec![]).fragment_len(0), 0);
+    }
+
+    #[test]
+    fn zero_fragments() {
+        let d = digest(40, 4, vec![(1, 2), (7, 1)]);
+        let zeros: Vec<u32> = (0..d.fragments).filter(|n| d.is_zero_fragment(*n)).collect();
+        assert_eq!(zeros, vec![1, 2, 7]);
+        assert_eq!(d.stored_fragments().collect::<Vec<_>>(), vec![0, 3, 4, 5, 6, 8, 9]);
+        assert_eq!(d.stored_fragment_count().unwrap(), 7);
+        assert!(!digest(40, 4, vec![]).is_zero_fragment(0));
+        assert_eq!(digest(40, 4, vec![(0, 10)]).stored_fragment_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn check_fragment() {
+        let mut d = digest(10, 4, vec![]);
+        let frags: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        // Legacy digests are only checked against the lengths
+        assert!(d.check_fragment(2, b"xy").is_ok());
+        assert!(d.check_fragment(2, b"xyz").is_err());
+
+        d.fragment_hashes = frags.iter().map(|f| zfs_fragment_hash(f)).collect();
+        for (n, f) in frags.iter().enumerate() {
+            assert!(d.check_fragment(n as u32, f).is_ok());
+        }
+        assert!(d.check_fragment(2, b"xy").is_err());
+        assert!(d.check_fragment(0, b"efgh").is_err());
+        assert!(d.check_fragment(3, b"").is_err());
+
+        // Neither a partial list of hashes nor too many are accepted
+        d.fragment_hashes.pop();
+        assert!(matches!(d.check_fragment(0, frags[0]), Err(ZfsError::Integrity(_))));
+        assert!(d.check_fragment(2, frags[2]).is_err());
+        d.fragment_hashes = frags.iter().chain(frags.iter()).map(|f| zfs_fragment_hash(f)).collect();
+        assert!(d.check_fragment(0, frags[0]).is_err());
+    }
+
+    #[test]
+    fn zero_fragments_overflow() {
+        assert!(digest(40, 4, vec![(0, 11)]).stored_fragment_count().is_err());
+        assert!(digest(40, 4, vec![(0, 6), (6, 6)]).stored_fragment_count().is_err());
+        assert!(digest(40, 4, vec![(0, u32::MAX), (0, 1)]).stored_fragment_count().is_err());
+    }
 }
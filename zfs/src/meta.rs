@@ -0,0 +1,308 @@
+use crate::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+///
+/// The metadata of a file that can be recorded in its digest on upload and
+/// restored on download.
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FileMetadata {
+    /// The unix permission bits, e.g. `0o755`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The modification time, in seconds since the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_nsec: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// The extended attributes, with hex encoded values.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+///
+/// Which metadata is recorded by an upload, or restored by a download.
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PreserveFlags {
+    #[serde(default)]
+    pub mode: bool,
+    #[serde(default)]
+    pub timestamps: bool,
+    #[serde(default)]
+    pub ownership: bool,
+    #[serde(default)]
+    pub xattrs: bool,
+    /// Also restores the setuid and setgid bits of the mode, and the xattrs
+    /// outside of the `user` namespace, e.g. `security.capability`. They are
+    /// dropped otherwise. Not part of `all`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub setid: bool,
+}
+
+impl PreserveFlags {
+    /// Parses a list of `mode`, `timestamps`, `ownership`, `xattr`, `all` or
+    /// `setid`.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> ZfsResult<PreserveFlags> {
+        let mut flags = PreserveFlags::default();
+        for v in values {
+            match v.trim() {
+                "mode" => flags.mode = true,
+                "timestamps" => flags.timestamps = true,
+                "ownership" => flags.ownership = true,
+                "xattr" => flags.xattrs = true,
+                "setid" => flags.setid = true,
+                "all" => {
+                    flags.mode = true;
+                    flags.timestamps = true;
+                    flags.ownership = true;
+                    flags.xattrs = true;
+                }
+                other => {
+                    return Err(ZfsError::Invalid(format!(
+                        "Unknown attribute to preserve: {}",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(flags)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PreserveFlags::default()
+    }
+}
+
+/// Reads the metadata of the file at `path` selected by `flags`.
+pub fn capture_metadata(path: &str, flags: PreserveFlags) -> ZfsResult<FileMetadata> {
+    let m = std::fs::metadata(path)?;
+    let mut meta = FileMetadata::default();
+    if flags.timestamps {
+        let mtime = filetime::FileTime::from_last_modification_time(&m);
+        meta.mtime = Some(mtime.unix_seconds());
+        meta.mtime_nsec = Some(mtime.nanoseconds());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if flags.mode {
+            meta.mode = Some(m.mode() & 0o7777);
+        }
+        if flags.ownership {
+            meta.uid = Some(m.uid());
+            meta.gid = Some(m.gid());
+        }
+    }
+    if flags.xattrs {
+        meta.xattrs = xattrs::read(Path::new(path))?;
+    }
+    Ok(meta)
+}
+
+/// Only the `user` xattrs are restored by default, the `security`, `trusted`
+/// and `system` ones may grant privileges or change the access control.
+fn is_restorable_xattr(name: &str, flags: PreserveFlags) -> bool {
+    name.starts_with("user.") || flags.setid
+}
+
+/// Applies the metadata of `meta` selected by `flags` to the file at `path`.
+/// This is best effort, e.g. only root can restore the ownership, thus the
+/// failures are logged rather than returned.
+///
+/// The setuid and setgid bits are only restored with `flags.setid`, and never
+/// on a file given the ownership recorded by the uploader, otherwise anyone
+/// able to upload could create setuid root files on the downloaders. The
+/// same goes for the xattrs outside of the `user` namespace.
+pub fn restore_metadata(path: &str, meta: &FileMetadata, flags: PreserveFlags) {
+    let warn = |what: &str, e: std::io::Error| {
+        log::warn!(target: "meta", "Unable to restore the {} of {}: {}", what, path, e)
+    };
+    if flags.xattrs {
+        for (name, value) in &meta.xattrs {
+            if !is_restorable_xattr(name, flags) {
+                log::info!(target: "meta", "Not restoring the xattr {} of {} without setid", name, path);
+                continue;
+            }
+            match hex::decode(value) {
+                Ok(value) => {
+                    if let Err(e) = xattrs::write(Path::new(path), name, &value) {
+                        warn(name, e);
+                    }
+                }
+                Err(_) => log::warn!(target: "meta", "Invalid value for the xattr {} of {}", name, path),
+            }
+        }
+    }
+    #[cfg(unix)]
+    {
+        let remote_owner = flags.ownership && (meta.uid.is_some() || meta.gid.is_some());
+        if remote_owner {
+            if let Err(e) = std::os::unix::fs::chown(path, meta.uid, meta.gid) {
+                warn("ownership", e);
+            }
+        }
+        if let Some(mode) = meta.mode.filter(|_| flags.mode) {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = match flags.setid && !remote_owner {
+                true => mode & 0o7777,
+                false => mode & 0o1777,
+            };
+            if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)) {
+                warn("mode", e);
+            }
+        }
+    }
+    if let Some(mtime) = meta.mtime.filter(|_| flags.timestamps) {
+        let mtime = filetime::FileTime::from_unix_time(mtime, meta.mtime_nsec.unwrap_or(0));
+        if let Err(e) = filetime::set_file_mtime(path, mtime) {
+            warn("modification time", e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod xattrs {
+    use crate::*;
+    use std::collections::BTreeMap;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_path(path: &Path) -> std::io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Calls `f` first to get the size of the buffer, then to fill it.
+    fn read_buf(f: impl Fn(*mut libc::c_void, usize) -> libc::ssize_t) -> std::io::Result<Vec<u8>> {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut buf = vec![0_u8; len as usize];
+        let len = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+
+    pub(super) fn read(path: &Path) -> ZfsResult<BTreeMap<String, String>> {
+        let cpath = c_path(path)?;
+        let names = read_buf(|buf, len| unsafe { libc::listxattr(cpath.as_ptr(), buf as *mut libc::c_char, len) })?;
+        let mut xattrs = BTreeMap::new();
+        for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+            let (Ok(cname), Ok(sname)) = (CString::new(name), std::str::from_utf8(name)) else {
+                continue;
+            };
+            match read_buf(|buf, len| unsafe { libc::getxattr(cpath.as_ptr(), cname.as_ptr(), buf, len) }) {
+                Ok(value) => {
+                    xattrs.insert(sname.to_string(), hex::encode(value));
+                }
+                Err(e) => log::warn!(target: "meta", "Unable to read the xattr {} of {:?}: {}", sname, path, e),
+            }
+        }
+        Ok(xattrs)
+    }
+
+    pub(super) fn write(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+        let cpath = c_path(path)?;
+        let cname = CString::new(name).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let r = unsafe {
+            libc::setxattr(
+                cpath.as_ptr(),
+                cname.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if r < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod xattrs {
+    use crate::*;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    pub(super) fn read(path: &Path) -> ZfsResult<BTreeMap<String, String>> {
+        log::warn!(target: "meta", "Extended attributes are not supported on this platform, not recording the ones of {:?}", path);
+        Ok(BTreeMap::new())
+    }
+
+    pub(super) fn write(_path: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "extended attributes are not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_preserve_flags() {
+        assert!(PreserveFlags::parse([]).unwrap().is_empty());
+        let flags = PreserveFlags::parse(["mode", " timestamps "]).unwrap();
+        assert!(flags.mode && flags.timestamps && !flags.ownership && !flags.xattrs && !flags.setid);
+        let all = PreserveFlags::parse(["all"]).unwrap();
+        assert!(all.mode && all.timestamps && all.ownership && all.xattrs);
+        assert!(!all.setid, "setid has to be asked for explicitly");
+        let flags = PreserveFlags::parse(["setid", "all"]).unwrap();
+        assert!(flags.setid && flags.ownership);
+        assert!(PreserveFlags::parse(["mode", "acl"]).is_err());
+        assert!(PreserveFlags::parse([""]).is_err());
+    }
+
+    #[test]
+    fn restore_user_xattrs_only() {
+        let names = ["user.mime_type", "security.capability", "security.selinux", "trusted.x", "system.posix_acl_access", "user"];
+        let restored = |flags| names.iter().filter(|n| is_restorable_xattr(n, flags)).copied().collect::<Vec<_>>();
+        assert_eq!(restored(PreserveFlags::parse(["all"]).unwrap()), vec!["user.mime_type"]);
+        assert_eq!(restored(PreserveFlags::parse(["xattr", "setid"]).unwrap()), names);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_mode_clears_setid() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let path = std::env::temp_dir().join(format!("zfs-meta-test-{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        std::fs::write(&path, b"x").unwrap();
+        let mode = |path: &str| std::fs::metadata(path).unwrap().mode() & 0o7777;
+        let meta = FileMetadata { mode: Some(0o6755), ..Default::default() };
+
+        restore_metadata(&path, &meta, PreserveFlags::parse(["mode"]).unwrap());
+        assert_eq!(mode(&path), 0o755);
+
+        let uid = std::fs::metadata(&path).unwrap().uid();
+        let owned = FileMetadata { uid: Some(uid), ..meta.clone() };
+        restore_metadata(&path, &owned, PreserveFlags::parse(["mode", "ownership", "setid"]).unwrap());
+        assert_eq!(mode(&path), 0o755);
+
+        restore_metadata(&path, &meta, PreserveFlags::parse(["mode", "setid"]).unwrap());
+        assert_eq!(mode(&path) & 0o4000, 0o4000);
+
+        // The sticky bit is kept
+        let sticky = FileMetadata { mode: Some(0o1644), ..Default::default() };
+        restore_metadata(&path, &sticky, PreserveFlags::parse(["mode"]).unwrap());
+        assert_eq!(mode(&path), 0o1644);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use crate::*;
+use futures::{StreamExt, TryStreamExt};
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, OnceLock};
+use zenoh::query::QueryTarget;
+use zenoh::Session;
+
+//
+// The upload of a file is published in two phases:
+//
+//  1. its fragments are put under a new generation, i.e. `zfs/<key>/<generation>/<n>`,
+//     where nobody is reading them yet;
+//  2. once every fragment has been put, and a storage has been checked to
+//     hold each of them, the digest is put on `zfs/<key>/zfs-digest` which
+//     commits the new generation. Once the digest is stored, the fragments of
+//     the previous generation are deleted.
+//
+// The upload job holds its slot of the scheduler from the fragmentation to the
+// commit, and each put takes a fragment slot. The puts that fail are retried
+// a few times, then the upload is paused and resumed later with the fragments
+// not put yet, or found missing.
+//
+// The generation of an upload is the id of its job, thus uploading needs the
+// job database.
+//
+
+/// The distinct fragments put by each upload job of this process.
+static PUT_FRAGMENTS: OnceLock<Mutex<HashMap<String, BTreeSet<u32>>>> = OnceLock::new();
+
+fn put_fragments() -> std::sync::MutexGuard<'static, HashMap<String, BTreeSet<u32>>> {
+    PUT_FRAGMENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Records that the fragment `n` of the upload `job_id` was put, and returns
+/// the number of distinct fragments put so far.
+fn record_put(job_id: &str, n: u32) -> u32 {
+    let mut puts = put_fragments();
+    let put = puts.entry(job_id.to_string()).or_default();
+    put.insert(n);
+    put.len() as u32
+}
+
+fn is_put(job_id: &str, n: u32) -> bool {
+    put_fragments().get(job_id).is_some_and(|put| put.contains(&n))
+}
+
+/// Forgets the fragments of the upload `job_id` found `missing`, so that they
+/// are put again when the upload resumes.
+fn forget_puts(job_id: &str, missing: &BTreeSet<u32>) {
+    if let Some(put) = put_fragments().get_mut(job_id) {
+        put.retain(|n| !missing.contains(n));
+    }
+}
+
+/// Puts the fragment staged at `path` on `key`, the transient failures are
+/// retried with a backoff.
+async fn put_staged_fragment(z: &Session, path: &str, key: &str) -> ZfsResult<()> {
+    let mut delay = UPLOAD_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match upload_fragment(z, path, key).await {
+            Err(e) if e.is_transient() && attempt < UPLOAD_RETRIES => {
+                log::info!(target: "publish", "Unable to put {}, retrying in {:?}: {}", key, delay, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// Pauses the upload `job_id` after `e`, it is resumed later with the
+/// fragments not put yet.
+fn pause_upload(job_id: &str, e: &ZfsError) {
+    zfs_update_job(job_id, |j| {
+        if matches!(j.state, JobState::Fragmenting | JobState::Transferring | JobState::Committing) {
+            j.state = JobState::Paused;
+        }
+        j.error = Some(e.to_string());
+    });
+}
+
+/// Puts the fragments staged for the upload `job` that were not put yet by
+/// this process, and returns the number of distinct fragments put. The puts
+/// share the fragment slots of the scheduler with the other jobs.
+pub async fn upload_staged_fragments(z: &Session, job: &Job) -> ZfsResult<u32> {
+    let frags_dir = zfsd_upload_frags_dir_for_key(&job.key)?;
+    let digest = read_defrag_digest(&frags_dir).await?;
+    let frags_dir = &frags_dir;
+    futures::stream::iter(digest.stored_fragments().filter(|i| !is_put(&job.id, *i)))
+        .map(Ok)
+        .try_for_each_concurrent(zfs_config().concurrency.max_inflight_fragments, |i| async move {
+            let path = format!("{}/{}", frags_dir, i);
+            let len = tokio::fs::metadata(&path).await.map_or(0, |m| m.len());
+            let _permit = zfs_admit_fragment(&job.id, job.priority).await;
+            zfs_throttle(&job.id, &job.key, &job.windows, len).await;
+            put_staged_fragment(z, &path, &zfs_frag_key(&job.key, Some(&job.id), i)).await?;
+            let put = record_put(&job.id, i);
+            zfs_update_job(&job.id, |j| j.transferred = put);
+            Ok::<(), ZfsError>(())
+        })
+        .await?;
+    Ok(put_fragments().get(&job.id).map_or(0, |put| put.len() as u32))
+}
+
+/// Puts the fragments staged for the upload `job_id`, then commits it. The
+/// caller holds the slot of the job meanwhile. Outside of its transfer window,
+/// or while no storage is reachable, the upload is paused instead, and resumed
+/// later by the sanitizer.
+pub async fn upload_job(z: &Session, job_id: &str) -> ZfsResult<()> {
+    let Some(job) = zfs_jobs().and_then(|db| db.get(job_id).ok().flatten()) else {
+        return Ok(());
+    };
+    if !zfs_may_transfer(job_id, &job.key, &job.windows) {
+        log::info!(target: "publish", "Not uploading {} before its transfer window or a storage", &job.key);
+        zfs_update_job(job_id, |j| j.state = JobState::Paused);
+        return Ok(());
+    }
+    zfs_update_job(job_id, |j| {
+        if j.state == JobState::Paused {
+            j.state = JobState::Transferring;
+        }
+    });
+    if let Err(e) = upload_staged_fragments(z, &job).await {
+        pause_upload(job_id, &e);
+        return Err(e);
+    }
+    maybe_commit_upload(z, job_id).await
+}
+
+/// Checks that a storage holds a valid copy of the fragment `n` of `digest`
+/// on `frag_key`. Only the closest storage is queried, the replicas are
+/// checked by the scrubbing.
+async fn is_stored(z: &Session, frag_key: &str, digest: &FragmentationDigest, n: u32) -> ZfsResult<()> {
+    let replies = z.get(frag_key).target(QueryTarget::BestMatching).await?;
+    let mut error = ZfsError::NotFound(format!("No storage holds {}", frag_key));
+    while let Ok(reply) = replies.recv_async().await {
+        match reply.result() {
+            Ok(sample) => match digest.check_fragment(n, &sample.payload().to_bytes()) {
+                Ok(()) => return Ok(()),
+                Err(e) => error = e,
+            },
+            Err(e) => error = ZfsError::NotFound(format!("{}: {}", frag_key, e.payload().try_to_string().unwrap_or_default())),
+        }
+    }
+    Err(error)
+}
+
+/// The stored fragments of `digest`, committed under `key`, that the
+/// storages do not hold a valid copy of.
+async fn missing_fragments(z: &Session, key: &str, digest: &FragmentationDigest) -> BTreeSet<u32> {
+    let max = zfs_config().concurrency.max_inflight_fragments;
+    futures::stream::iter(digest.stored_fragments())
+        .map(|n| async move {
+            let frag_key = zfs_frag_key(key, digest.generation.as_deref(), n);
+            is_stored(z, &frag_key, digest, n)
+                .await
+                .map_err(|e| log::debug!(target: "publish", "{} is not stored: {}", &frag_key, e))
+                .err()
+                .map(|_| n)
+        })
+        .buffer_unordered(max)
+        .filter_map(|n| async move { n })
+        .collect()
+        .await
+}
+
+/// The keys of the fragments of `previous` that are replaced by the commit
+/// of `digest` under `key`, none when the generation is the same.
+fn replaced_fragment_keys(key: &str, previous: &FragmentationDigest, digest: &FragmentationDigest) -> Vec<String> {
+    if previous.generation == digest.generation {
+        return vec![];
+    }
+    previous
+        .stored_fragments()
+        .map(|i| zfs_frag_key(key, previous.generation.as_deref(), i))
+        .collect()
+}
+
+/// Commits the upload `job_id` if all its fragments are stored.
+pub async fn maybe_commit_upload(z: &Session, job_id: &str) -> ZfsResult<()> {
+    let ready = Cell::new(false);
+    // The digest put while no storage is reachable would be lost
+    let available = zfs_storage_available();
+    let job = zfs_update_job(job_id, |j| {
+        ready.set(j.state == JobState::Transferring && j.transferred >= j.fragments);
+        if ready.get() {
+            j.state = if available { JobState::Committing } else { JobState::Paused };
+        }
+    });
+    ready.set(ready.get() && available);
+    match job {
+        Some(job) if ready.get() => match commit_upload(z, &job).await {
+            Ok(()) => {
+                put_fragments().remove(job_id);
+                zfs_update_job(job_id, |j| {
+                    j.state = JobState::Completed;
+                    j.error = None;
+                });
+                Ok(())
+            }
+            Err(e) => {
+                // Resumed with the missing fragments
+                pause_upload(job_id, &e);
+                Err(e)
+            }
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Checks that every fragment of `job` is stored, puts its digest, then
+/// deletes the fragments of the generation it replaces, if any, once the
+/// digest is stored.
+async fn commit_upload(z: &Session, job: &Job) -> ZfsResult<()> {
+    let digest = read_defrag_digest(&zfsd_upload_frags_dir_for_key(&job.key)?).await?;
+    let digest_key = zfs_frags_digest_for_key(&job.key);
+    let previous = get_fragmentation_digest(z, &digest_key).await.ok();
+
+    let missing = missing_fragments(z, &job.key, &digest).await;
+    if !missing.is_empty() {
+        forget_puts(&job.id, &missing);
+        return Err(ZfsError::NotFound(format!(
+            "{} fragments of {} are not stored, putting them again",
+            missing.len(),
+            &job.key
+        )));
+    }
+
+    log::info!(target: "publish", "Committing {} generation {:?}", &job.key, &digest.generation);
+    z.put(&digest_key, zfs_encode_digest(&digest)?)
+        .congestion_control(zenoh::qos::CongestionControl::Block)
+        .await?;
+    let stored = get_fragmentation_digest(z, &digest_key).await?;
+    if stored.generation != digest.generation || stored.crc != digest.crc {
+        return Err(ZfsError::NotFound(format!("The digest of {} is not stored", &job.key)));
+    }
+
+    let mut event = ZfsEvent::new(z, ZfsEventKind::Committed, &job.key, &digest);
+    if let Some(previous) = previous.filter(|p| p.generation != digest.generation) {
+        event.kind = ZfsEventKind::Replaced;
+        event.previous_generation = previous.generation.clone();
+        log::info!(target: "publish", "Deleting {} generation {:?}", &job.key, &previous.generation);
+        for frag_key in replaced_fragment_keys(&job.key, &previous, &digest) {
+            if let Err(e) = z.delete(&frag_key).await {
+                log::warn!(target: "publish", "Unable to delete {}: {}", frag_key, e);
+            }
+        }
+    }
+    publish_event(z, &event).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(generation: &str, fragments: u32) -> FragmentationDigest {
+        FragmentationDigest {
+            name: "a/b".into(),
+            size: fragments as u64 * 1024,
+            crc: 0,
+            fragment_size: 1024,
+            fragments,
+            expires: None,
+            signature: None,
+            generation: Some(generation.into()),
+            metadata: None,
+            zero_fragments: vec![],
+            adaptive_fragment_size: false,
+            fragment_hashes: vec![],
+        }
+    }
+
+    #[test]
+    fn commit_replaces_the_previous_generation() {
+        let previous = digest("old", 2);
+        let keys = replaced_fragment_keys("a/b", &previous, &digest("new", 3));
+        assert_eq!(keys, vec![zfs_frag_key("a/b", Some("old"), 0), zfs_frag_key("a/b", Some("old"), 1)]);
+        assert!(keys.iter().all(|k| k.contains("/old/")));
+        // Committing the same generation again deletes nothing
+        assert!(replaced_fragment_keys("a/b", &previous, &digest("old", 2)).is_empty());
+    }
+
+    #[test]
+    fn missing_fragments_are_put_again() {
+        let job_id = "publish-test-job";
+        for n in 0..4 {
+            record_put(job_id, n);
+        }
+        assert_eq!(record_put(job_id, 3), 4);
+        forget_puts(job_id, &BTreeSet::from([1, 3]));
+        assert!(is_put(job_id, 0) && is_put(job_id, 2));
+        assert!(!is_put(job_id, 1) && !is_put(job_id, 3));
+        assert_eq!(record_put(job_id, 1), 3);
+        put_fragments().remove(job_id);
+        assert!(!is_put(job_id, 0));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::*;
+use zenoh::key_expr::KeyExpr;
+use zenoh::Session;
+
+/// The quotas of `quotas` that apply to the zfs key `zkey`.
+fn quotas_for<'a>(quotas: &'a [QuotaConfig], zkey: &KeyExpr<'_>) -> Vec<(KeyExpr<'static>, &'a QuotaConfig)> {
+    quotas
+        .iter()
+        .filter_map(|q| KeyExpr::try_from(q.prefix.clone()).ok().map(|ke| (ke, q)))
+        .filter(|(ke, _)| ke.includes(zkey))
+        .collect()
+}
+
+fn includes(quota_ke: &KeyExpr<'_>, key: &str) -> bool {
+    KeyExpr::try_from(zfs_key(key)).is_ok_and(|k| quota_ke.includes(&k))
+}
+
+/// The number of bytes of `stored` under `quota_ke`, plus the ones of the
+/// uploads of `jobs` in progress, without counting `key` itself as it is
+/// going to be replaced.
+fn usage(
+    stored: &[(String, FragmentationDigest)],
+    jobs: &[Job],
+    quota_ke: &KeyExpr<'_>,
+    key: &str,
+    job_id: &str,
+) -> u64 {
+    let stored = stored
+        .iter()
+        .filter(|(k, _)| k != key && includes(quota_ke, k))
+        .fold(0u64, |used, (_, digest)| used.saturating_add(digest.size));
+    jobs.iter()
+        .filter(|j| j.kind == JobKind::Upload && j.is_active() && j.id != job_id && j.key != key)
+        .filter(|j| includes(quota_ke, &j.key))
+        .fold(stored, |used, j| used.saturating_add(j.size))
+}
+
+/// Checks `size` more bytes under `key` against every quota of `quotas`
+/// that applies to it.
+fn check_usage(
+    quotas: &[(KeyExpr<'static>, &QuotaConfig)],
+    stored: &[(String, FragmentationDigest)],
+    jobs: &[Job],
+    key: &str,
+    job_id: &str,
+    size: u64,
+) -> ZfsResult<()> {
+    for (ke, quota) in quotas {
+        let used = usage(stored, jobs, ke, key, job_id);
+        log::debug!(target: "quota", "{} uses {} of {} bytes", ke, used, quota.max_bytes);
+        if used.checked_add(size).is_none_or(|total| total > quota.max_bytes) {
+            return Err(ZfsError::QuotaExceeded(format!(
+                "uploading {} bytes under {} would exceed the quota of {}: {} of {} bytes already used",
+                size, key, ke, used, quota.max_bytes
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that storing `size` bytes under `key` does not exceed any of the
+/// configured quotas. The stored files are listed once for all the quotas.
+pub async fn check_quota(z: &Session, key: &str, job_id: &str, size: u64) -> ZfsResult<()> {
+    let zkey = KeyExpr::try_from(zfs_key(key))?;
+    let quotas = quotas_for(&zfs_config().quotas, &zkey);
+    if quotas.is_empty() {
+        return Ok(());
+    }
+    let stored = list_stored_digests(z).await?;
+    let jobs = zfs_jobs().map(|db| db.list()).unwrap_or_default();
+    check_usage(&quotas, &stored, &jobs, key, job_id, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(prefix: &str, max_bytes: u64) -> QuotaConfig {
+        QuotaConfig { prefix: prefix.into(), max_bytes }
+    }
+
+    fn stored(key: &str, size: u64) -> (String, FragmentationDigest) {
+        let digest = FragmentationDigest {
+            name: key.into(),
+            size,
+            crc: 0,
+            fragment_size: 1024,
+            fragments: 0,
+            expires: None,
+            signature: None,
+            generation: None,
+            metadata: None,
+            zero_fragments: vec![],
+            adaptive_fragment_size: false,
+            fragment_hashes: vec![],
+        };
+        (key.into(), digest)
+    }
+
+    fn upload(id: &str, key: &str, size: u64, state: JobState) -> Job {
+        let mut job = Job::new(id, JobKind::Upload, key, "/tmp/f");
+        job.size = size;
+        job.state = state;
+        job
+    }
+
+    #[test]
+    fn matching_quotas() {
+        let quotas = [quota("zfs/team-a/**", 10), quota("zfs/**", 100), quota("zfs/team-b/**", 10), quota("a//b", 1)];
+        let zkey = KeyExpr::try_from(zfs_key("team-a/x")).unwrap();
+        let matched: Vec<&str> = quotas_for(&quotas, &zkey).iter().map(|(_, q)| q.prefix.as_str()).collect();
+        assert_eq!(matched, vec!["zfs/team-a/**", "zfs/**"]);
+    }
+
+    #[test]
+    fn enforce_quotas() {
+        let quotas = [quota("zfs/team-a/**", 100)];
+        let zkey = KeyExpr::try_from(zfs_key("team-a/new")).unwrap();
+        let quotas = quotas_for(&quotas, &zkey);
+        let files = [stored("team-a/x", 40), stored("team-b/y", 1000), stored("team-a/new", 50)];
+        let jobs = [
+            upload("u1", "team-a/z", 30, JobState::Transferring),
+            upload("u2", "team-a/done", 1000, JobState::Completed),
+            upload("self", "team-a/other", 1000, JobState::Fragmenting),
+        ];
+        // The file replaced and the job itself do not count
+        assert_eq!(usage(&files, &jobs, &quotas[0].0, "team-a/new", "self"), 70);
+        assert!(check_usage(&quotas, &files, &jobs, "team-a/new", "self", 30).is_ok());
+        assert!(matches!(
+            check_usage(&quotas, &files, &jobs, "team-a/new", "self", 31),
+            Err(ZfsError::QuotaExceeded(_))
+        ));
+        assert!(check_usage(&quotas, &files, &jobs, "team-a/new", "self", u64::MAX).is_err());
+    }
+
+    #[test]
+    fn usage_saturates() {
+        let quotas = [quota("zfs/**", u64::MAX)];
+        let zkey = KeyExpr::try_from(zfs_key("a")).unwrap();
+        let quotas = quotas_for(&quotas, &zkey);
+        let files = [stored("b", u64::MAX), stored("c", u64::MAX)];
+        assert_eq!(usage(&files, &[], &quotas[0].0, "a", "j"), u64::MAX);
+        assert!(check_usage(&quotas, &files, &[], "a", "j", 1).is_err());
+        assert!(check_usage(&quotas, &[], &[], "a", "j", u64::MAX).is_ok());
+    }
+}
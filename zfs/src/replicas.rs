@@ -0,0 +1,204 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use zenoh::query::{ConsolidationMode, QueryTarget, Reply};
+use zenoh::Session;
+
+static REPLICAS: OnceLock<Mutex<HashMap<String, ReplicaScore>>> = OnceLock::new();
+
+///
+/// What this process remembers about a replica, i.e. a storage or a peer
+/// answering fragment queries.
+///
+#[derive(Debug, Clone)]
+pub struct ReplicaScore {
+    /// The recent failures, each valid reply forgives one of them.
+    pub failures: u32,
+    pub last_failure: Option<Instant>,
+}
+
+impl ReplicaScore {
+    /// Records a failure at `now`, the failures older than `period` are
+    /// forgotten.
+    fn fail(&mut self, now: Instant, period: Duration) {
+        if self.last_failure.is_some_and(|t| now.duration_since(t) >= period) {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last_failure = Some(now);
+    }
+
+    /// Records a valid reply, returns true when the score can be forgotten.
+    fn succeed(&mut self) -> bool {
+        self.failures = self.failures.saturating_sub(1);
+        self.failures == 0
+    }
+
+    fn is_down_ranked(&self, now: Instant, after: u32, period: Duration) -> bool {
+        self.failures >= after && self.last_failure.is_some_and(|t| now.duration_since(t) < period)
+    }
+}
+
+fn replicas() -> std::sync::MutexGuard<'static, HashMap<String, ReplicaScore>> {
+    REPLICAS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// The id of the replica that sent `reply`, i.e. `<zid>/<entity id>`.
+pub fn zfs_replica_id(reply: &Reply) -> Option<String> {
+    reply
+        .replier_id()
+        .map(|id| format!("{}/{}", id.zid(), id.eid()))
+}
+
+fn record_failure(replica: Option<&str>) {
+    if let Some(replica) = replica {
+        let conf = &zfs_config().replicas;
+        let mut replicas = replicas();
+        let score = replicas.entry(replica.to_string()).or_insert(ReplicaScore {
+            failures: 0,
+            last_failure: None,
+        });
+        score.fail(Instant::now(), conf.down_rank_period());
+        if score.failures == conf.down_rank_after {
+            log::warn!(target: "replicas", "Down-ranking the replica {} after {} failures", replica, score.failures);
+        }
+    }
+}
+
+fn record_success(replica: Option<&str>) {
+    if let Some(replica) = replica {
+        let mut replicas = replicas();
+        if replicas.get_mut(replica).is_some_and(|score| score.succeed()) {
+            replicas.remove(replica);
+        }
+    }
+}
+
+/// True when `replica` failed too often recently, its replies are then only
+/// used when no other replica has a valid one.
+pub fn zfs_is_down_ranked(replica: &str) -> bool {
+    let conf = &zfs_config().replicas;
+    replicas()
+        .get(replica)
+        .is_some_and(|score| score.is_down_ranked(Instant::now(), conf.down_rank_after, conf.down_rank_period()))
+}
+
+/// The replicas with recent failures that were not forgiven yet.
+pub fn zfs_replica_scores() -> HashMap<String, ReplicaScore> {
+    replicas().clone()
+}
+
+/// Queries every replica holding `key` and returns the first reply accepted
+/// by `validate`. The replies of down-ranked replicas are only used when no
+/// other replica has a valid one.
+pub async fn fetch_from_replicas(
+    z: &Session,
+    key: &str,
+    validate: impl Fn(&[u8]) -> ZfsResult<()>,
+) -> ZfsResult<Vec<u8>> {
+    let replies = z
+        .get(key)
+        .target(QueryTarget::All)
+        .consolidation(ConsolidationMode::None)
+        .await?;
+
+    let mut fallback = None;
+    let mut last_error = None;
+    while let Ok(reply) = replies.recv_async().await {
+        let replica = zfs_replica_id(&reply);
+        match reply.result() {
+            Ok(sample) => {
+                let bs = sample.payload().to_bytes();
+                match validate(&bs) {
+                    Ok(()) if replica.as_deref().is_some_and(zfs_is_down_ranked) => {
+                        log::debug!(target: "replicas", "Keeping the reply of the down-ranked {:?} for {} as a fallback", &replica, key);
+                        if fallback.is_none() {
+                            fallback = Some((replica, bs.to_vec()));
+                        }
+                    }
+                    Ok(()) => {
+                        record_success(replica.as_deref());
+                        return Ok(bs.to_vec());
+                    }
+                    Err(e) => {
+                        log::info!(target: "replicas", "Invalid reply from {:?} for {}: {}", &replica, key, e);
+                        record_failure(replica.as_deref());
+                        last_error = Some(e);
+                    }
+                }
+            }
+            Err(e) => {
+                let msg = e.payload().try_to_string().unwrap_or_default().to_string();
+                log::debug!(target: "replicas", "Error reply from {:?} for {}: {}", &replica, key, &msg);
+                record_failure(replica.as_deref());
+                last_error = Some(ZfsError::NotFound(format!("Unable to retrieve {}: {}", key, msg)));
+            }
+        }
+    }
+    match (fallback, last_error) {
+        (Some((replica, bs)), _) => {
+            record_success(replica.as_deref());
+            Ok(bs)
+        }
+        (None, Some(e)) => Err(e),
+        (None, None) => Err(ZfsError::NotFound(format!("No reply for: {}", key))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    fn score() -> ReplicaScore {
+        ReplicaScore { failures: 0, last_failure: None }
+    }
+
+    #[test]
+    fn down_rank_after_failures() {
+        let now = Instant::now();
+        let mut s = score();
+        s.fail(now, PERIOD);
+        s.fail(now, PERIOD);
+        assert!(!s.is_down_ranked(now, 3, PERIOD));
+        s.fail(now, PERIOD);
+        assert!(s.is_down_ranked(now, 3, PERIOD));
+        // Until the period has passed since the last failure
+        assert!(s.is_down_ranked(now + PERIOD / 2, 3, PERIOD));
+        assert!(!s.is_down_ranked(now + PERIOD, 3, PERIOD));
+    }
+
+    #[test]
+    fn a_success_forgives_one_failure() {
+        let now = Instant::now();
+        let mut s = score();
+        for _ in 0..3 {
+            s.fail(now, PERIOD);
+        }
+        assert!(!s.succeed());
+        assert!(!s.is_down_ranked(now, 3, PERIOD));
+        // A flaky replica is down-ranked despite its valid replies
+        s.fail(now, PERIOD);
+        assert!(s.is_down_ranked(now, 3, PERIOD));
+        assert!(!s.succeed() && !s.succeed());
+        assert!(s.succeed());
+        assert!(s.succeed(), "the score does not go below zero");
+        assert_eq!(s.failures, 0);
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let now = Instant::now();
+        let mut s = score();
+        s.fail(now, PERIOD);
+        s.fail(now, PERIOD);
+        s.fail(now + PERIOD, PERIOD);
+        assert_eq!(s.failures, 1);
+        assert!(!s.is_down_ranked(now + PERIOD, 2, PERIOD));
+    }
+}
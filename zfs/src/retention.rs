@@ -0,0 +1,167 @@
+use crate::*;
+use std::sync::Arc;
+use zenoh::query::QueryTarget;
+use zenoh::Session;
+
+/// Parses a duration such as `90`, `30s`, `15m`, `12h`, `7d` or `2w` into
+/// seconds (no suffix means seconds).
+pub fn zfs_parse_duration(s: &str) -> ZfsResult<u64> {
+    let s = s.trim();
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n: u64 = n
+        .parse()
+        .map_err(|_| ZfsError::Invalid(format!("Invalid duration: {}", s)))?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        _ => return Err(ZfsError::Invalid(format!("Invalid duration unit in: {}", s))),
+    };
+    n.checked_mul(factor)
+        .ok_or_else(|| ZfsError::Invalid(format!("Duration too long: {}", s)))
+}
+
+/// Parses a UTC date, either `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM:SS` or seconds
+/// since the UNIX epoch, into seconds since the UNIX epoch.
+pub fn zfs_parse_date(s: &str) -> ZfsResult<u64> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    let invalid = || ZfsError::Invalid(format!("Invalid date: {}", s));
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+    let ymd: Vec<u64> = date
+        .split('-')
+        .map(|v| v.parse().map_err(|_| invalid()))
+        .collect::<ZfsResult<_>>()?;
+    let hms: Vec<u64> = time
+        .trim_end_matches('Z')
+        .split(':')
+        .map(|v| v.parse().map_err(|_| invalid()))
+        .collect::<ZfsResult<_>>()?;
+    if ymd.len() != 3 || hms.len() != 3 {
+        return Err(invalid());
+    }
+    let (y, m, d) = (ymd[0], ymd[1], ymd[2]);
+    if !(1970..=9999).contains(&y) || !(1..=12).contains(&m) || !(1..=31).contains(&d) || hms[0] > 23 || hms[1] > 59 || hms[2] > 60 {
+        return Err(invalid());
+    }
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok(days * 86400 + hms[0] * 3600 + hms[1] * 60 + hms[2])
+}
+
+/// Deletes the file stored under `key`, the digest is removed first so that
+/// readers stop seeing the file before its fragments go away.
+pub async fn delete_file(z: &Session, key: &str, digest: &FragmentationDigest) -> ZfsResult<()> {
+    z.delete(zfs_frags_digest_for_key(key)).await?;
+    for i in digest.stored_fragments() {
+        z.delete(zfs_frag_key(key, digest.generation.as_deref(), i)).await?;
+    }
+    publish_event(z, &ZfsEvent::new(z, ZfsEventKind::Deleted, key, digest)).await;
+    Ok(())
+}
+
+/// Retrieves all the fragmentation digests currently stored, together with
+/// their key.
+pub async fn list_stored_digests(z: &Session) -> ZfsResult<Vec<(String, FragmentationDigest)>> {
+    let prefix = format!("{}/", zfs_base_dir());
+    let suffix = format!("/{}", ZFS_DIGEST);
+    let replies = z
+        .get(format!("{}**/{}", prefix, ZFS_DIGEST))
+        .target(QueryTarget::All)
+        .await?;
+    let mut digests = Vec::new();
+    while let Ok(reply) = replies.recv_async().await {
+        if let Ok(sample) = reply.result() {
+            let ke = sample.key_expr().as_str();
+            let key = ke
+                .strip_prefix(&prefix)
+                .and_then(|k| k.strip_suffix(&suffix))
+                .map(|k| k.to_string());
+            match (key, zfs_decode_digest(&sample.payload().to_bytes())) {
+                (Some(key), Ok(digest)) => {
+                    if !digests.iter().any(|(k, _)| k == &key) {
+                        digests.push((key, digest));
+                    }
+                }
+                (_, Err(e)) => log::warn!(target: "retention", "Invalid digest stored under {}: {}", ke, e),
+                (None, _) => log::warn!(target: "retention", "Unexpected digest key {}", ke),
+            }
+        }
+    }
+    Ok(digests)
+}
+
+/// Deletes every stored file whose expiry date has passed, returns the number
+/// of files deleted.
+pub async fn expire_keys(z: &Session) -> ZfsResult<usize> {
+    let now = zfs_now();
+    let mut n = 0;
+    for (key, digest) in list_stored_digests(z).await? {
+        if digest.expires.is_some_and(|t| t <= now) {
+            log::info!(target: "retention", "{} expired, deleting it", &key);
+            match delete_file(z, &key, &digest).await {
+                Ok(()) => n += 1,
+                Err(e) => log::warn!(target: "retention", "Unable to delete {}: {}", &key, e),
+            }
+        }
+    }
+    Ok(n)
+}
+
+/// Periodically deletes the expired files from the storage.
+pub async fn retention_scanner(z: Arc<Session>) {
+    let conf = zfs_config();
+    loop {
+        tokio::time::sleep(conf.retention_period()).await;
+        log::debug!(target: "retention", "Looking for expired keys...");
+        if let Err(e) = expire_keys(&z).await {
+            log::warn!(target: "retention", "Unable to scan the stored digests: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration() {
+        assert_eq!(zfs_parse_duration("90").unwrap(), 90);
+        assert_eq!(zfs_parse_duration(" 30s ").unwrap(), 30);
+        assert_eq!(zfs_parse_duration("15m").unwrap(), 15 * 60);
+        assert_eq!(zfs_parse_duration("12h").unwrap(), 12 * 3600);
+        assert_eq!(zfs_parse_duration("7d").unwrap(), 7 * 24 * 3600);
+        assert_eq!(zfs_parse_duration("2w").unwrap(), 14 * 24 * 3600);
+        for s in ["", "h", "-5", "1.5h", "3y", "10 d", "99999999999999999w"] {
+            assert!(zfs_parse_duration(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_date() {
+        assert_eq!(zfs_parse_date("0").unwrap(), 0);
+        assert_eq!(zfs_parse_date("1700000000").unwrap(), 1_700_000_000);
+        assert_eq!(zfs_parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(zfs_parse_date("2000-03-01").unwrap(), 951_868_800);
+        // Leap day
+        assert_eq!(zfs_parse_date("2024-02-29").unwrap(), 1_709_164_800);
+        assert_eq!(zfs_parse_date("2024-02-29T12:30:15").unwrap(), 1_709_164_800 + 45_015);
+        assert_eq!(zfs_parse_date("2024-02-29T12:30:15Z").unwrap(), 1_709_164_800 + 45_015);
+        for s in ["", "2024-", "2024-02", "1969-12-31", "2024-13-01", "2024-01-32", "2024-01-01T24:00:00", "2024-01-01T12:00", "99999999999999-01-01", "tomorrow"] {
+            assert!(zfs_parse_date(s).is_err(), "{}", s);
+        }
+    }
+}
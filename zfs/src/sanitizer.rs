@@ -3,88 +3,200 @@ use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use zenoh::Session;
 
-async fn cleanup_download(digest: &DownloadDigest, download_manifest: &str) -> Result<(), String> {
+#[derive(Debug)]
+struct SanitizerRegistryEntry {
+    digest: std::sync::Arc<DownloadDigest>,
+    job_id: String,
+    tide_level: usize,
+    gap_nun: usize,
+    stuck_cycles: usize,
+}
+
+impl SanitizerRegistryEntry {
+    fn persist(&self) {
+        zfs_update_job(&self.job_id, |j| {
+            j.sanitizer = Some(SanitizerState {
+                tide_level: self.tide_level,
+                gap_num: self.gap_nun,
+                stuck_cycles: self.stuck_cycles,
+            });
+        });
+    }
+}
+
+async fn cleanup_download(digest: &DownloadDigest, download_manifest: &str) -> ZfsResult<()> {
     // Check first if the file has been really created
     let target = std::path::Path::new(&digest.path);
-    let frags_path = zfsd_download_frags_dir_for_key(&digest.key);
+    let frags_path = zfsd_download_frags_dir_for_key(&digest.key)?;
     let fmanif_exists = std::path::Path::new(&format!("{}/{}", &frags_path, ZFS_DIGEST)).exists();
-    if target.exists() && fmanif_exists {
-        let defrag_digest = read_defrag_digest(&frags_path).await.unwrap();
-        let size = target.metadata().unwrap().len();
+    // The file being replaced is only downloaded once it is the new one
+    let downloaded = if digest.replace {
+        match read_defrag_digest(&frags_path).await {
+            Ok(d) => crate::follow::is_up_to_date(target, d.size, d.crc).await,
+            Err(_) => false,
+        }
+    } else {
+        target.exists()
+    };
+    if downloaded && fmanif_exists {
+        let defrag_digest = read_defrag_digest(&frags_path).await?;
+        let size = target.metadata()?.len();
 
-        tokio::time::sleep(Duration::from_secs(2 * FS_EVT_DELAY)).await;
+        tokio::time::sleep(2 * zfs_config().fs_evt_delay()).await;
         if size == defrag_digest.size {
-            let frags_path = zfsd_download_frags_dir_for_key(&digest.key);
-            let _ignore = std::fs::remove_dir_all(&frags_path);
+            let job_id = zfs_job_id(std::path::Path::new(download_manifest));
+            // The staging is kept for the other downloads of the same key
+            let shared = zfs_jobs().is_some_and(|db| {
+                db.list().iter().any(|j| {
+                    j.kind == JobKind::Download && j.key == digest.key && j.is_active() && j.id != job_id
+                })
+            });
+            if !shared {
+                let _ignore = std::fs::remove_dir_all(&frags_path);
+            }
             let _ignore = std::fs::remove_file(std::path::Path::new(download_manifest));
+            zfs_update_job(&job_id, |j| {
+                j.state = JobState::Completed;
+                j.transferred = j.fragments;
+                j.sanitizer = None;
+            });
         } else {
             log::debug!(
                 "The target {} is still being reassembled, clean up will be scheduled later {} != {}",&digest.path, size, defrag_digest.size,
 
             );
         }
-    } else if !target.exists() && fmanif_exists {
+    } else if !downloaded && fmanif_exists {
         // We try to defragment...
-        let _ignore = defragment(&digest.key, &digest.path).await;
+        if let Err(e) = defragment(&digest.key, &digest.path, digest.preserve).await {
+            log::warn!("Unable to defragment {}: {}", &digest.key, e);
+        }
     }
     Ok(())
 }
 
-async fn compute_download_gaps(z: std::sync::Arc<Session>, digest: &DownloadDigest) -> Result<BTreeSet<usize>, String> {
-    let frags_path = zfsd_download_frags_dir_for_key(&digest.key);
-    let frag_digest_key = zfs_frags_digest_for_key(&digest.key);
-    if let Ok(defrag_digest) = download_fragmentation_digest(z, &frag_digest_key).await {
-        let mut frag_set = BTreeSet::new();
-        for i in 0..defrag_digest.fragments {
-            frag_set.insert(i as usize);
-        }
-        let path = std::path::Path::new(&frags_path);
-        if let Ok(entries) = path.read_dir() {
-            for entry in entries.flatten() {
-                let name = entry
-                    .path()
-                    .file_name()
-                    .and_then(|s| s.to_str())
-                    .unwrap()
-                    .to_string();
-                if let Ok(n) = name.parse() {
-                    frag_set.remove(&n);
+/// Retrieves the fragmentation digest of `key` from the storage and stages it
+/// for its download.
+async fn fetch_download_digest(z: std::sync::Arc<Session>, key: &str) -> ZfsResult<FragmentationDigest> {
+    let defrag_digest = download_fragmentation_digest(z, &zfs_frags_digest_for_key(key)).await?;
+    prepare_download_staging(key, &defrag_digest).await?;
+    Ok(defrag_digest)
+}
+
+/// Computes the fragments still missing for `digest`, together with the
+/// fragmentation digest they have to be retrieved for. Partially written and
+/// truncated fragments count as missing and are removed.
+///
+/// The fragmentation digest, which can be large, is only retrieved from the
+/// storage when `fetch` is true or none is staged yet, and else read from the
+/// download staging.
+async fn compute_download_gaps(
+    z: std::sync::Arc<Session>,
+    digest: &DownloadDigest,
+    fetch: bool,
+) -> ZfsResult<(FragmentationDigest, BTreeSet<usize>)> {
+    let frags_path = zfsd_download_frags_dir_for_key(&digest.key)?;
+    let defrag_digest = match read_defrag_digest(&frags_path).await {
+        Ok(staged) if !fetch => staged,
+        _ => fetch_download_digest(z, &digest.key).await?,
+    };
+    let mut frag_set = BTreeSet::new();
+    for i in defrag_digest.stored_fragments() {
+        frag_set.insert(i as usize);
+    }
+    let path = std::path::Path::new(&frags_path);
+    if let Ok(entries) = path.read_dir() {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if zfs_is_tmp_path(&name) {
+                // Left over by a crash, unless it is still being written
+                let age = entry.metadata().ok().and_then(|m| m.modified().ok()).and_then(|t| t.elapsed().ok());
+                if age.is_some_and(|age| age > zfs_config().gc_period()) {
+                    let _ignore = std::fs::remove_file(entry.path());
+                }
+            } else if let Ok(n) = name.parse::<u32>() {
+                let len = entry.metadata().map(|m| m.len()).ok();
+                if len == Some(defrag_digest.fragment_len(n)) {
+                    frag_set.remove(&(n as usize));
+                } else {
+                    log::info!(target: "sanitizer", "Discarding the truncated fragment {:?}", entry.path());
+                    let _ignore = std::fs::remove_file(entry.path());
                 }
             }
         }
-        Ok(frag_set)
-    } else {
-        Err(format!("Unable to read defrag digest for {:?}", &digest))
     }
+    Ok((defrag_digest, frag_set))
 }
 
 fn compute_acceleration_factor(stuck_cycles: usize) -> usize {
-    let r = (stuck_cycles / STUCK_CYCLES_RESET) + 1;
+    let conf = zfs_config();
+    let r = (stuck_cycles / conf.sanitizer.stuck_cycles_reset) + 1;
     let a = std::cmp::max(1, r / 2);
-    let f = std::cmp::min(a * r, MAX_ACCELERATION);
+    let f = std::cmp::min(a * r, conf.concurrency.max_acceleration);
     log::debug!("Acceleration factor for {} is {}", stuck_cycles, f);
     f
 }
 pub async fn download_sanitizer(z: Arc<zenoh::Session>) {
+    let conf = zfs_config();
     let mut registry = HashMap::<String, SanitizerRegistryEntry>::new();
-    let d3 = zfsd_download_digest_dir();
+    let d3 = match zfsd_download_digest_dir() {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!(target: "sanitizer", "Unable to run the download sanitizer: {}", e);
+            return;
+        }
+    };
     let dpath = std::path::Path::new(&d3);
     loop {
-        tokio::time::sleep(SANITIZER_PERIOD).await;
+        tokio::time::sleep(conf.sanitizer_period()).await;
+        if !zfs_storage_available() {
+            // Not a stall, the jobs resume as soon as a storage is back
+            log::debug!(target: "sanitizer", "No storage is reachable, waiting for one");
+            zfs_wait_storage().await;
+        }
         log::debug!("Running Sanitizer...");
         if let Ok(entries) = dpath.read_dir() {
             for entry in entries.flatten() {
                 log::debug!("Sanitizer looking into <{:?}>", &entry);
-                match registry.get_mut(entry.path().to_str().unwrap()) {
+                let entry_path = entry.path().to_string_lossy().to_string();
+                // Followers are completed by the download they follow, and
+                // pending jobs wait for the other jobs on their key
+                let job_id = zfs_job_id(entry.path().as_path());
+                let pending = matches!(
+                    zfs_jobs().map(|db| db.get(&job_id)),
+                    Some(Ok(Some(Job { state: JobState::Pending, .. })))
+                );
+                if pending || zfs_is_follower(&job_id) {
+                    log::debug!(target: "sanitizer", "Skipping job {}, it is waiting for another job", &job_id);
+                    continue;
+                }
+                match registry.get_mut(&entry_path) {
                     Some(reg_entry) => {
                         log::debug!("Registry {:?} exists for  <{:?}>", &reg_entry, &entry);
-                        if let Ok(gap_set) = compute_download_gaps(z.clone(), &reg_entry.digest).await {
+                        // Not a stall, the job waits for its transfer window
+                        let spec = &reg_entry.digest;
+                        if let WindowState::Closed(_) = zfs_window_state(&job_id, &spec.key, &spec.windows) {
+                            log::debug!(target: "sanitizer", "Skipping job {}, its transfer window is closed", &job_id);
+                            zfs_update_job(&job_id, |j| {
+                                if j.is_active() {
+                                    j.state = JobState::Paused;
+                                }
+                            });
+                            continue;
+                        }
+                        zfs_update_job(&job_id, |j| {
+                            if j.state == JobState::Paused {
+                                j.state = JobState::Transferring;
+                            }
+                        });
+                        if let Ok((frag_digest, gap_set)) = compute_download_gaps(z.clone(), &reg_entry.digest, false).await {
+                            let frag_digest = Arc::new(frag_digest);
                             let mut gaps: Vec<usize> = gap_set.into_iter().collect();
                             if gaps.is_empty() {
                                 log::debug!("Found <<NO GAPS>> for {:?}", &reg_entry.digest);
-                                cleanup_download(&reg_entry.digest, entry.path().to_str().unwrap())
-                                    .await
-                                    .unwrap();
+                                if let Err(e) = cleanup_download(&reg_entry.digest, &entry_path).await {
+                                    log::warn!("Unable to clean up {:?}: {}", &reg_entry.digest, e);
+                                }
                             } else {
                                 log::info!("Found <<GAPS>> for {:?},  repairing", &reg_entry.digest);
                                 gaps.sort_unstable();
@@ -95,7 +207,7 @@ pub async fn download_sanitizer(z: Arc<zenoh::Session>) {
                                     .filter(|n| *n >= reg_entry.tide_level)
                                     .collect();
 
-                                let delta = reg_entry.gap_nun - new_gap_num;
+                                let delta = reg_entry.gap_nun.saturating_sub(new_gap_num);
                                 log::debug!("Gaps delta is :\n\t{:?}", delta);
                                 if delta > 0 {
                                     log::debug!("Udating tide and gaps");
@@ -103,23 +215,40 @@ pub async fn download_sanitizer(z: Arc<zenoh::Session>) {
                                     reg_entry.gap_nun = new_gap_num;
                                 } else {
                                     reg_entry.stuck_cycles += 1;
-                                    if reg_entry.stuck_cycles % STUCK_CYCLES_RESET == 0 {
+                                    if reg_entry.stuck_cycles % conf.sanitizer.stuck_cycles_reset == 0 {
                                         log::info!(
                                             "Gaps recovery for {:?} seems to have stalled, this may be due to process restart of disconnections. Restarting fragment sanitiser.",
                                             &reg_entry.digest.key);
                                         reg_entry.tide_level = 0;
+                                        // The file may have been replaced meanwhile, its gaps are
+                                        // then computed again on the next cycle. Checked with a
+                                        // backoff as the digest can be large
+                                        let resets = reg_entry.stuck_cycles / conf.sanitizer.stuck_cycles_reset;
+                                        let replaced = resets.is_power_of_two()
+                                            && match fetch_download_digest(z.clone(), &reg_entry.digest.key).await {
+                                                Ok(d) => d.generation != frag_digest.generation || d.crc != frag_digest.crc,
+                                                Err(e) => {
+                                                    log::debug!(target: "sanitizer", "Unable to refresh the digest of {}: {}", &reg_entry.digest.key, e);
+                                                    false
+                                                }
+                                            };
+                                        // Not piling up fragments still waiting for the scheduler
                                         let n = std::cmp::min(
                                             gaps.len(),
-                                            GAP_DOWNLOAD_SCHEDULE
+                                            conf.concurrency.gap_download_schedule
                                                 * compute_acceleration_factor(
                                                     reg_entry.stuck_cycles,
                                                 ),
-                                        );
+                                        )
+                                        .saturating_sub(zfs_scheduled_fragments(&reg_entry.job_id));
+                                        let n = if replaced { 0 } else { n };
                                         for i in 0..n {
                                             reg_entry.tide_level = *gaps.get(i).unwrap();
                                             tokio::task::spawn(download_fragment(
                                                 z.clone(),
-                                                reg_entry.digest.key.clone(),
+                                                reg_entry.job_id.clone(),
+                                                reg_entry.digest.clone(),
+                                                frag_digest.clone(),
                                                 reg_entry.tide_level as u32,
                                             ));
                                         }
@@ -131,43 +260,71 @@ pub async fn download_sanitizer(z: Arc<zenoh::Session>) {
                                         );
                                     }
                                 }
+                                reg_entry.persist();
                             }
                         } else {
                             log::info!("Unable to compute gap for {:?}, the fragmentation manifest may be missing...", entry);
                         }
                     }
                     None => {
-                        let digest = zfs_read_download_digest_from(entry.path().as_path())
-                            .await
-                            .unwrap();
+                        let digest = match zfs_read_download_digest_from(entry.path().as_path()).await {
+                            Ok(digest) => digest,
+                            Err(e) => {
+                                log::warn!(target: "sanitizer", "Unable to read download digest {:?}: {}", &entry, e);
+                                continue;
+                            }
+                        };
                         log::debug!(target: "sanitizer", "Download Digest: {:?}", &digest);
-                        let mut gaps: Vec<usize> = compute_download_gaps(z.clone(), &digest)
-                            .await
-                            .unwrap()
-                            .into_iter()
-                            .collect();
+                        let mut gaps: Vec<usize> = match compute_download_gaps(z.clone(), &digest, true).await {
+                            Ok((_, gaps)) => gaps.into_iter().collect(),
+                            Err(e) => {
+                                log::info!("Unable to compute gap for {:?}: {}", &entry, e);
+                                continue;
+                            }
+                        };
                         gaps.sort_unstable();
 
                         if !gaps.is_empty() {
-                            let tide_level = *gaps.first().unwrap();
-                            let gap_nun = gaps.len();
+                            let job_id = zfs_job_id(entry.path().as_path());
+                            // Restart from the state saved before a zfsd restart, if any
+                            let saved = match zfs_jobs().map(|db| db.get(&job_id)) {
+                                Some(Ok(Some(job))) => job.sanitizer,
+                                Some(Ok(None)) => {
+                                    // The digest was written while zfsd was not running
+                                    let mut job = Job::new(&job_id, JobKind::Download, &digest.key, &digest.path);
+                                    job.priority = digest.priority;
+                                    job.windows = digest.windows.clone();
+                                    job.hooks = digest.hooks.clone();
+                                    job.state = JobState::Transferring;
+                                    zfs_put_job(&job);
+                                    None
+                                }
+                                _ => None,
+                            };
+                            // The gaps filled meanwhile then count as progress
+                            let (tide_level, gap_nun, stuck_cycles) = match saved {
+                                Some(s) if s.gap_num > 0 => (s.tide_level, s.gap_num, s.stuck_cycles),
+                                _ => (*gaps.first().unwrap(), gaps.len(), 0),
+                            };
                             let sre = SanitizerRegistryEntry {
                                 digest: Arc::new(digest),
+                                job_id,
                                 tide_level,
                                 gap_nun,
-                                stuck_cycles: 0,
+                                stuck_cycles,
                             };
+                            sre.persist();
                             log::debug!(
                                 "Created registry entry {:?} exists for  <{:?}>",
                                 &sre,
                                 &entry.path()
                             );
-                            registry.insert(entry.path().to_str().unwrap().into(), sre);
+                            registry.insert(entry_path, sre);
                         } else {
                             log::info!("Sanitizer completed downloading for {:?} -- cleaning up.", &digest.key);
-                            cleanup_download(&digest, entry.path().to_str().unwrap())
-                                .await
-                                .unwrap();
+                            if let Err(e) = cleanup_download(&digest, &entry_path).await {
+                                log::warn!("Unable to clean up {:?}: {}", &digest, e);
+                            }
                         }
                     }
                 }
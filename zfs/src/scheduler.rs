@@ -0,0 +1,371 @@
+use crate::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use tokio::sync::oneshot;
+
+static SCHEDULER: OnceLock<Mutex<Scheduler>> = OnceLock::new();
+
+//
+// The scheduler of zfsd admits the jobs and the fragment transfers:
+//
+//  - at most `concurrency.max_jobs` jobs run at the same time, the others
+//    wait, shown as `Pending`, and are admitted by priority then in order
+//    of arrival;
+//  - at most `concurrency.max_inflight_fragments` fragments are downloaded
+//    at the same time. A free slot goes to the highest priority job waiting
+//    for one, and among jobs of the same priority to the one with the
+//    fewest fragments in flight, thus jobs share the bandwidth fairly.
+//
+// The permits are created before waiting for a slot, thus a slot granted to
+// a waiter that gave up, e.g. a cancelled job, is given back when its permit
+// is dropped.
+//
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = ZfsError;
+
+    fn from_str(s: &str) -> ZfsResult<Priority> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(ZfsError::Invalid(format!("Invalid priority: {}", s))),
+        }
+    }
+}
+
+struct Waiter {
+    priority: Priority,
+    job_id: String,
+    seq: u64,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    running: HashSet<String>,
+    waiting_jobs: Vec<Waiter>,
+    inflight: usize,
+    job_inflight: HashMap<String, usize>,
+    waiting_fragments: Vec<Waiter>,
+    seq: u64,
+}
+
+impl Scheduler {
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Admits the waiting jobs while there is room.
+    fn admit_jobs(&mut self) {
+        self.admit_jobs_up_to(zfs_config().concurrency.max_jobs);
+    }
+
+    fn admit_jobs_up_to(&mut self, max_jobs: usize) {
+        while self.running.len() < max_jobs {
+            let Some(i) = (0..self.waiting_jobs.len())
+                .max_by_key(|i| (self.waiting_jobs[*i].priority, std::cmp::Reverse(self.waiting_jobs[*i].seq)))
+            else {
+                break;
+            };
+            let w = self.waiting_jobs.swap_remove(i);
+            // The job may have given up waiting
+            if w.tx.send(()).is_ok() {
+                self.running.insert(w.job_id);
+            }
+        }
+    }
+
+    /// Gives the free fragment slots to the waiting jobs.
+    fn admit_fragments(&mut self) {
+        self.admit_fragments_up_to(zfs_config().concurrency.max_inflight_fragments);
+    }
+
+    fn admit_fragments_up_to(&mut self, max: usize) {
+        while self.inflight < max {
+            let Some(i) = (0..self.waiting_fragments.len()).max_by_key(|i| {
+                let w = &self.waiting_fragments[*i];
+                let inflight = self.job_inflight.get(&w.job_id).copied().unwrap_or(0);
+                (w.priority, std::cmp::Reverse(inflight), std::cmp::Reverse(w.seq))
+            }) else {
+                break;
+            };
+            let w = self.waiting_fragments.swap_remove(i);
+            if w.tx.send(()).is_ok() {
+                self.inflight += 1;
+                *self.job_inflight.entry(w.job_id).or_default() += 1;
+            }
+        }
+    }
+}
+
+fn scheduler() -> MutexGuard<'static, Scheduler> {
+    SCHEDULER
+        .get_or_init(|| Mutex::new(Scheduler::default()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Allows a job to run until it is dropped.
+pub struct JobPermit {
+    job_id: String,
+    priority: Priority,
+    /// False for a job admitted twice, or given its slot back.
+    counted: bool,
+    /// Receives the slot of the job while it waits for one.
+    pending: Option<oneshot::Receiver<()>>,
+}
+
+impl JobPermit {
+    /// Gives the slot of the job back to the other jobs, e.g. while it is
+    /// paused, until it is admitted again.
+    pub fn release(&mut self) {
+        let mut s = scheduler();
+        // The slot may have been granted after the wait was cancelled
+        if let Some(mut rx) = self.pending.take() {
+            self.counted = rx.try_recv().is_ok();
+            drop(rx);
+            s.waiting_jobs.retain(|w| !w.tx.is_closed());
+        }
+        if std::mem::take(&mut self.counted) {
+            s.running.remove(&self.job_id);
+            s.admit_jobs();
+        }
+    }
+
+    /// Waits until the job is admitted again after `release`.
+    pub async fn readmit(&mut self) {
+        if !self.counted {
+            *self = zfs_admit_job(&self.job_id, self.priority).await;
+        }
+    }
+}
+
+impl Drop for JobPermit {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Waits until the job `job_id` is allowed to run. A job that is already
+/// running gets a permit that does not count twice.
+pub async fn zfs_admit_job(job_id: &str, priority: Priority) -> JobPermit {
+    let mut permit = JobPermit {
+        job_id: job_id.to_string(),
+        priority,
+        counted: false,
+        pending: None,
+    };
+    {
+        let mut s = scheduler();
+        if s.running.contains(job_id) {
+            return permit;
+        }
+        let (tx, rx) = oneshot::channel();
+        let seq = s.next_seq();
+        s.waiting_jobs.push(Waiter {
+            priority,
+            job_id: job_id.to_string(),
+            seq,
+            tx,
+        });
+        permit.pending = Some(rx);
+        s.admit_jobs();
+    }
+    if let Some(rx) = permit.pending.as_mut() {
+        let admitted = rx.await.is_ok();
+        permit.pending = None;
+        permit.counted = admitted;
+        if !admitted {
+            log::warn!(target: "scheduler", "Job {} was dropped from the queue, running it anyway", job_id);
+        }
+    }
+    permit
+}
+
+/// Allows a fragment transfer until it is dropped.
+pub struct FragmentPermit {
+    job_id: String,
+    /// Receives the slot of the transfer while it waits for one.
+    pending: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for FragmentPermit {
+    fn drop(&mut self) {
+        let mut s = scheduler();
+        // The slot may have been granted after the wait was cancelled
+        if let Some(mut rx) = self.pending.take() {
+            let granted = rx.try_recv().is_ok();
+            drop(rx);
+            s.waiting_fragments.retain(|w| !w.tx.is_closed());
+            if !granted {
+                return;
+            }
+        }
+        s.inflight = s.inflight.saturating_sub(1);
+        if let Some(n) = s.job_inflight.get_mut(&self.job_id) {
+            *n = n.saturating_sub(1);
+            if *n == 0 {
+                s.job_inflight.remove(&self.job_id);
+            }
+        }
+        s.admit_fragments();
+    }
+}
+
+/// Waits for a slot to transfer a fragment for the job `job_id`.
+pub async fn zfs_admit_fragment(job_id: &str, priority: Priority) -> FragmentPermit {
+    let mut permit = FragmentPermit {
+        job_id: job_id.to_string(),
+        pending: None,
+    };
+    {
+        let mut s = scheduler();
+        let (tx, rx) = oneshot::channel();
+        let seq = s.next_seq();
+        s.waiting_fragments.push(Waiter {
+            priority,
+            job_id: job_id.to_string(),
+            seq,
+            tx,
+        });
+        permit.pending = Some(rx);
+        s.admit_fragments();
+    }
+    if let Some(rx) = permit.pending.as_mut() {
+        // The slot is always sent while the permit waits for it
+        if rx.await.is_ok() {
+            permit.pending = None;
+        }
+    }
+    permit
+}
+
+/// The number of fragments of the job `job_id` in flight or waiting for a
+/// slot.
+pub fn zfs_scheduled_fragments(job_id: &str) -> usize {
+    let s = scheduler();
+    s.job_inflight.get(job_id).copied().unwrap_or(0)
+        + s.waiting_fragments.iter().filter(|w| w.job_id == job_id).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::task::{Context, Poll};
+
+    fn wait(waiters: &mut Vec<Waiter>, seq: &mut u64, job_id: &str, priority: Priority) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        *seq += 1;
+        waiters.push(Waiter { priority, job_id: job_id.into(), seq: *seq, tx });
+        rx
+    }
+
+    fn admitted(rx: &mut oneshot::Receiver<()>) -> bool {
+        rx.try_recv().is_ok()
+    }
+
+    #[test]
+    fn admit_jobs_by_priority() {
+        let mut s = Scheduler::default();
+        let mut seq = 0;
+        let mut low = wait(&mut s.waiting_jobs, &mut seq, "low", Priority::Low);
+        let mut first = wait(&mut s.waiting_jobs, &mut seq, "first", Priority::Normal);
+        let mut second = wait(&mut s.waiting_jobs, &mut seq, "second", Priority::Normal);
+        let mut high = wait(&mut s.waiting_jobs, &mut seq, "high", Priority::High);
+        s.admit_jobs_up_to(2);
+        assert!(admitted(&mut high) && admitted(&mut first));
+        assert!(!admitted(&mut second) && !admitted(&mut low));
+
+        s.running.remove("high");
+        s.admit_jobs_up_to(2);
+        assert!(admitted(&mut second) && !admitted(&mut low));
+
+        // The waiters that gave up are skipped
+        drop(low);
+        let mut later = wait(&mut s.waiting_jobs, &mut seq, "later", Priority::Low);
+        s.running.clear();
+        s.admit_jobs_up_to(2);
+        assert!(admitted(&mut later));
+        assert_eq!(s.running, HashSet::from(["later".to_string()]));
+    }
+
+    #[test]
+    fn share_fragments_fairly() {
+        let mut s = Scheduler::default();
+        let mut seq = 0;
+        // A job that asked first for many fragments does not starve the others
+        let mut a: Vec<_> = (0..4).map(|_| wait(&mut s.waiting_fragments, &mut seq, "a", Priority::Normal)).collect();
+        let mut b: Vec<_> = (0..2).map(|_| wait(&mut s.waiting_fragments, &mut seq, "b", Priority::Normal)).collect();
+        let mut low = wait(&mut s.waiting_fragments, &mut seq, "c", Priority::Low);
+        s.admit_fragments_up_to(4);
+        assert_eq!(a.iter_mut().map(admitted).filter(|a| *a).count(), 2);
+        assert_eq!(b.iter_mut().map(admitted).filter(|a| *a).count(), 2);
+        assert_eq!(s.job_inflight.get("a"), Some(&2));
+        assert!(!admitted(&mut low));
+
+        let mut high = wait(&mut s.waiting_fragments, &mut seq, "d", Priority::High);
+        s.inflight -= 1;
+        s.admit_fragments_up_to(4);
+        assert!(admitted(&mut high) && !admitted(&mut low));
+    }
+
+    fn poll_once<F: Future>(f: std::pin::Pin<&mut F>) -> Option<F::Output> {
+        match f.poll(&mut Context::from_waker(futures::task::noop_waker_ref())) {
+            Poll::Ready(v) => Some(v),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn cancelled_waits_give_their_slot_back() {
+        let conf = &zfs_config().concurrency;
+        // Every job slot is taken, then one is freed for the waiting job
+        // after its wait was polled, but before it completes.
+        let mut running: Vec<JobPermit> = (0..conf.max_jobs)
+            .map(|i| {
+                let id = format!("cancel-running-{}", i);
+                let permit = poll_once(std::pin::pin!(zfs_admit_job(&id, Priority::Normal)));
+                permit.unwrap()
+            })
+            .collect();
+        let mut waiting = Box::pin(zfs_admit_job("cancel-waiting", Priority::High));
+        assert!(poll_once(waiting.as_mut()).is_none());
+        running.pop();
+        assert!(scheduler().running.contains("cancel-waiting"));
+        drop(waiting);
+        assert!(!scheduler().running.contains("cancel-waiting"));
+        drop(running);
+        assert!(scheduler().running.is_empty());
+
+        // The same goes for the fragment slots
+        let mut inflight: Vec<FragmentPermit> = (0..conf.max_inflight_fragments)
+            .map(|_| poll_once(std::pin::pin!(zfs_admit_fragment("cancel-fragments", Priority::Normal))).unwrap())
+            .collect();
+        let mut waiting = Box::pin(zfs_admit_fragment("cancel-fragment", Priority::High));
+        assert!(poll_once(waiting.as_mut()).is_none());
+        inflight.pop();
+        assert_eq!(scheduler().job_inflight.get("cancel-fragment"), Some(&1));
+        drop(waiting);
+        assert_eq!(scheduler().job_inflight.get("cancel-fragment"), None);
+        // A wait cancelled before its slot was granted takes nothing
+        inflight.push(poll_once(std::pin::pin!(zfs_admit_fragment("cancel-fragments", Priority::Normal))).unwrap());
+        let mut waiting = Box::pin(zfs_admit_fragment("cancel-fragment", Priority::High));
+        assert!(poll_once(waiting.as_mut()).is_none());
+        drop(waiting);
+        assert_eq!(zfs_scheduled_fragments("cancel-fragment"), 0);
+        drop(inflight);
+        assert_eq!(scheduler().inflight, 0);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::time::Instant;
+use zenoh::qos::CongestionControl;
+use zenoh::query::{ConsolidationMode, QueryTarget};
+use zenoh::Session;
+
+//
+// Bit rot in a storage goes unnoticed until the file is downloaded. The
+// scrubber walks the stored digests and fetches every stored fragment from
+// every replica, at up to `scrub.max_bps` bits per second, and checks each
+// copy against the hash recorded in the digest. Only the digests whose
+// signature verifies are scrubbed, so that a forged digest cannot get valid
+// fragments overwritten. The corrupt copies are
+// reported and, when repairing, overwritten by putting a valid copy again,
+// taken from another replica or else from the swarm.
+//
+// There is no parity to rebuild a fragment from, thus the fragments of which
+// no valid copy is found are only reported. The fragments of the files
+// uploaded before the hashes were recorded are only checked for length.
+//
+
+///
+/// The corrupt fragments found in the replicas of a key.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubReport {
+    pub key: String,
+    /// The number of stored fragments checked.
+    pub fragments: u32,
+    /// False when the digest records no hashes, the fragments were then only
+    /// checked for length.
+    pub hashed: bool,
+    /// The replicas holding a corrupt copy of each corrupt fragment.
+    pub corrupt: BTreeMap<u32, BTreeSet<String>>,
+    pub repaired: Vec<u32>,
+    /// The corrupt fragments of which no valid copy was found.
+    pub unrecoverable: Vec<u32>,
+}
+
+/// Paces the fetches to `max_bps` bits per second (0 means unlimited). The
+/// fetches wait before querying the replicas, for as many copies as the
+/// previous fragment had.
+struct Throttle {
+    max_bps: u64,
+    next: Instant,
+    copies: u64,
+}
+
+impl Throttle {
+    fn new(max_bps: u64) -> Throttle {
+        Throttle { max_bps, next: Instant::now(), copies: 1 }
+    }
+
+    /// Reserves the time to fetch `bytes` at `now`, returns when the fetch
+    /// may start.
+    fn reserve(&mut self, now: Instant, bytes: u64) -> Instant {
+        if self.max_bps == 0 {
+            return now;
+        }
+        let start = self.next.max(now);
+        self.next = start + Duration::from_secs_f64(bytes as f64 * 8.0 / self.max_bps as f64);
+        start
+    }
+
+    /// Waits before fetching every copy of a fragment of `len` bytes.
+    async fn wait(&mut self, len: u64) {
+        let start = self.reserve(Instant::now(), len.saturating_mul(self.copies));
+        tokio::time::sleep_until(start).await;
+    }
+
+    /// Accounts for the copies fetched beyond the expected ones.
+    fn fetched(&mut self, len: u64, copies: u64) {
+        if copies > self.copies {
+            self.reserve(Instant::now(), len.saturating_mul(copies - self.copies));
+        }
+        self.copies = copies.max(1);
+    }
+}
+
+/// Fetches the fragment `n` of `key` from every replica, returns a valid copy
+/// if any, and the replicas whose copy is corrupt.
+async fn fetch_fragment_copies(
+    z: &Session,
+    key: &str,
+    digest: &FragmentationDigest,
+    n: u32,
+    throttle: &mut Throttle,
+) -> ZfsResult<(Option<Vec<u8>>, BTreeSet<String>)> {
+    let frag_key = zfs_frag_key(key, digest.generation.as_deref(), n);
+    let len = digest.fragment_len(n);
+    throttle.wait(len).await;
+    let replies = z
+        .get(&frag_key)
+        .target(QueryTarget::All)
+        .consolidation(ConsolidationMode::None)
+        .await?;
+    let mut valid = None;
+    let mut corrupt = BTreeSet::new();
+    let mut copies = 0;
+    while let Ok(reply) = replies.recv_async().await {
+        let replica = zfs_replica_id(&reply).unwrap_or_else(|| "unknown".into());
+        // Missing copies are left to the replication check
+        let Ok(sample) = reply.result() else {
+            continue;
+        };
+        let bs = sample.payload().to_bytes();
+        copies += 1;
+        match digest.check_fragment(n, &bs) {
+            Ok(()) => {
+                valid.get_or_insert_with(|| bs.to_vec());
+            }
+            Err(e) => {
+                log::warn!(target: "scrub", "{} holds a corrupt copy of {}: {}", &replica, &frag_key, e);
+                corrupt.insert(replica);
+            }
+        }
+    }
+    throttle.fetched(len, copies);
+    Ok((valid, corrupt))
+}
+
+/// Checks every copy of the stored fragments of `key`, and repairs the corrupt
+/// ones when `repair` is set.
+async fn scrub_file(
+    z: &Session,
+    key: &str,
+    digest: &FragmentationDigest,
+    throttle: &mut Throttle,
+    repair: bool,
+) -> ZfsResult<ScrubReport> {
+    let mut report = ScrubReport {
+        key: key.to_string(),
+        fragments: 0,
+        hashed: !digest.fragment_hashes.is_empty(),
+        corrupt: BTreeMap::new(),
+        repaired: Vec::new(),
+        unrecoverable: Vec::new(),
+    };
+    let mut unchanged = None;
+    for n in digest.stored_fragments() {
+        let (valid, corrupt) = fetch_fragment_copies(z, key, digest, n, throttle).await?;
+        report.fragments += 1;
+        if corrupt.is_empty() {
+            continue;
+        }
+        report.corrupt.insert(n, corrupt);
+        if !repair {
+            if valid.is_none() {
+                report.unrecoverable.push(n);
+            }
+            continue;
+        }
+        // Not repairing a key replaced or deleted since it was listed, that
+        // would bring back the fragments of the previous generation.
+        if unchanged.is_none() {
+            let current = get_fragmentation_digest(z, &zfs_frags_digest_for_key(key)).await?;
+            unchanged = Some(current.generation == digest.generation && current.crc == digest.crc);
+        }
+        if unchanged == Some(false) {
+            log::info!(target: "scrub", "{} changed since it was listed, not repairing it", key);
+            break;
+        }
+        let frag_key = zfs_frag_key(key, digest.generation.as_deref(), n);
+        let valid = match valid {
+            Some(bs) => Some(bs),
+            None if zfs_config().swarm.enabled => fetch_from_peers(z, &frag_key, |bs| digest.check_fragment(n, bs))
+                .await
+                .map_err(|e| log::debug!(target: "scrub", "No valid copy of {} in the swarm: {}", &frag_key, e))
+                .ok(),
+            None => None,
+        };
+        match valid {
+            Some(bs) => {
+                log::info!(target: "scrub", "Repairing {}", &frag_key);
+                z.put(&frag_key, bs)
+                    .congestion_control(CongestionControl::Block)
+                    .await?;
+                report.repaired.push(n);
+            }
+            None => {
+                log::error!(target: "scrub", "No valid copy of {} was found, unable to repair it", &frag_key);
+                report.unrecoverable.push(n);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Scrubs every stored key, or only `keys` when not empty, fetching at up to
+/// `max_bps` bits per second (0 means unlimited), and repairs the corrupt
+/// fragments when `repair` is set.
+pub async fn zfs_scrub(z: &Session, keys: &[String], max_bps: u64, repair: bool) -> ZfsResult<Vec<ScrubReport>> {
+    let mut throttle = Throttle::new(max_bps);
+    let mut reports = Vec::new();
+    for (key, digest) in list_stored_digests(z).await? {
+        if !keys.is_empty() && !keys.contains(&key) {
+            continue;
+        }
+        if let Err(e) = verify_digest(&digest) {
+            log::warn!(target: "scrub", "Not scrubbing {}: {}", &key, e);
+            continue;
+        }
+        if digest.fragment_hashes.is_empty() {
+            log::debug!(target: "scrub", "{} records no fragment hashes, only checking their length", &key);
+        }
+        match scrub_file(z, &key, &digest, &mut throttle, repair).await {
+            Ok(report) => {
+                if !report.corrupt.is_empty() {
+                    log::warn!(
+                        target: "scrub",
+                        "{} has {} corrupt fragments, {} repaired, {} unrecoverable",
+                        &key,
+                        report.corrupt.len(),
+                        report.repaired.len(),
+                        report.unrecoverable.len()
+                    );
+                }
+                reports.push(report);
+            }
+            Err(e) => log::warn!(target: "scrub", "Unable to scrub {}: {}", &key, e),
+        }
+    }
+    Ok(reports)
+}
+
+/// Periodically scrubs, and repairs, the stored files.
+pub async fn scrubber(z: Arc<Session>) {
+    let conf = zfs_config();
+    loop {
+        tokio::time::sleep(conf.scrub.period()).await;
+        log::debug!(target: "scrub", "Scrubbing the stored keys...");
+        if let Err(e) = zfs_scrub(&z, &[], conf.scrub.max_bps, conf.scrub.repair).await {
+            log::warn!(target: "scrub", "Unable to scrub the stored keys: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_the_fetches() {
+        let mut throttle = Throttle::new(8_000);
+        let now = Instant::now();
+        // 1000 bytes take a second at 8kbps
+        assert_eq!(throttle.reserve(now, 1_000), now);
+        assert_eq!(throttle.reserve(now, 500), now + Duration::from_secs(1));
+        assert_eq!(throttle.next, now + Duration::from_millis(1_500));
+        // The time not used is not saved for later
+        let later = now + Duration::from_secs(10);
+        assert_eq!(throttle.reserve(later, 1_000), later);
+
+        let mut unlimited = Throttle::new(0);
+        assert_eq!(unlimited.reserve(now, u64::MAX), now);
+        assert_eq!(unlimited.reserve(now, u64::MAX), now);
+    }
+
+    #[test]
+    fn throttle_every_copy() {
+        let mut throttle = Throttle::new(8_000);
+        let before = throttle.next;
+        // Three copies fetched when one was expected
+        throttle.fetched(1_000, 3);
+        assert!(throttle.next >= before + Duration::from_secs(2));
+        assert_eq!(throttle.copies, 3);
+        let next = throttle.next;
+        throttle.fetched(1_000, 2);
+        assert_eq!((throttle.next, throttle.copies), (next, 2));
+        throttle.fetched(1_000, 0);
+        assert_eq!(throttle.copies, 1);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::*;
+use zenoh::bytes::ZBytes;
+
+//
+// When zfsd and the zenoh router holding the storage run on the same host,
+// the fragments can be put, and served to the swarm, from zenoh shared memory:
+// with `shm.enabled` the payloads are read from the staged files straight
+// into buffers of a shared memory pool, which the local peers map instead of
+// receiving a copy through the network stack.
+//
+// This requires zfsd to be built with the `shm` feature, and shared memory
+// to be enabled in the zenoh configuration of both ends, as it is by default.
+// Payloads that do not fit in the pool are sent as usual.
+//
+
+#[cfg(feature = "shm")]
+mod provider {
+    use crate::*;
+    use std::io::Read;
+    use std::sync::OnceLock;
+    use zenoh::bytes::ZBytes;
+    use zenoh::shm::{GarbageCollect, PosixShmProviderBackend, ShmProvider, ShmProviderBuilder};
+    use zenoh::Wait;
+
+    static PROVIDER: OnceLock<Option<ShmProvider<PosixShmProviderBackend>>> = OnceLock::new();
+
+    fn provider() -> Option<&'static ShmProvider<PosixShmProviderBackend>> {
+        PROVIDER
+            .get_or_init(|| {
+                let pool_size = zfs_config().shm.pool_size;
+                ShmProviderBuilder::default_backend(pool_size)
+                    .wait()
+                    .inspect(|_| log::info!(target: "shm", "Allocated a shared memory pool of {} bytes", pool_size))
+                    .map_err(|e| log::warn!(target: "shm", "Unable to allocate the shared memory pool: {}", e))
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    /// Reads the `len` bytes long file at `path` into a shared memory buffer,
+    /// returns `None` when the pool has no room for it.
+    pub(super) fn read_shm(path: &str, len: usize) -> ZfsResult<Option<ZBytes>> {
+        let Some(provider) = provider().filter(|_| len > 0) else {
+            return Ok(None);
+        };
+        let mut buf = match provider.alloc(len).with_policy::<GarbageCollect>().wait() {
+            Ok(buf) => buf,
+            Err(e) => {
+                log::debug!(target: "shm", "Unable to allocate {} bytes, not using shared memory: {:?}", len, e);
+                return Ok(None);
+            }
+        };
+        std::fs::File::open(path)?.read_exact(&mut buf)?;
+        Ok(Some(buf.into()))
+    }
+}
+
+/// Reads the fragment staged at `path` into a payload, allocated in shared
+/// memory when enabled.
+pub async fn zfs_read_payload(path: &str) -> ZfsResult<ZBytes> {
+    #[cfg(feature = "shm")]
+    if zfs_config().shm.enabled {
+        let len = tokio::fs::metadata(path).await?.len() as usize;
+        let path = path.to_string();
+        let r = tokio::task::spawn_blocking(move || provider::read_shm(&path, len))
+            .await
+            .map_err(std::io::Error::other)??;
+        if let Some(bs) = r {
+            return Ok(bs);
+        }
+    }
+    Ok(tokio::fs::read(path).await?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staged(name: &str, bs: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("zfs-shm-test-{}-{}", name, std::process::id()));
+        std::fs::write(&path, bs).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn read_payloads() {
+        let bs: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let path = staged("payload", &bs);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let payload = rt.block_on(zfs_read_payload(&path)).unwrap();
+        assert_eq!(payload.to_bytes(), bs);
+        assert!(rt.block_on(zfs_read_payload(&format!("{}.missing", path))).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "shm")]
+    #[test]
+    fn read_into_shared_memory() {
+        let bs = vec![7u8; 4096];
+        let path = staged("shm", &bs);
+        assert!(provider::read_shm(&path, 0).unwrap().is_none());
+        // Without room in the pool, e.g. no shared memory, the payload is read as usual
+        if let Some(payload) = provider::read_shm(&path, bs.len()).unwrap() {
+            assert_eq!(payload.to_bytes(), bs);
+        }
+        // A file shorter than expected is never truncated into a payload
+        assert!(!matches!(provider::read_shm(&path, bs.len() + 1), Ok(Some(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use crate::*;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::sync::OnceLock;
+
+static SIGNING_KEY: OnceLock<Option<SigningKey>> = OnceLock::new();
+
+///
+/// The signature of a `FragmentationDigest`. It covers the exact json
+/// encoding of the digest that is stored next to it, thus verifiers do not
+/// need to know every field of the digest.
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DigestSignature {
+    pub signer: String,
+    /// The hex encoded ed25519 public key of the signer.
+    pub public_key: String,
+    /// The hex encoded ed25519 signature.
+    pub signature: String,
+    /// The signed encoding of the digest.
+    #[serde(skip)]
+    pub payload: String,
+}
+
+///
+/// A signed digest as stored: the json encoding of the digest and its
+/// signature. Unsigned digests are stored as is.
+///
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignedDigest {
+    payload: String,
+    signature: DigestSignature,
+}
+
+/// Encodes `digest` as stored, in a signed envelope when it is signed.
+pub fn zfs_encode_digest(digest: &FragmentationDigest) -> ZfsResult<Vec<u8>> {
+    match &digest.signature {
+        Some(signature) => Ok(serde_json::to_vec(&SignedDigest {
+            payload: signature.payload.clone(),
+            signature: signature.clone(),
+        })?),
+        None => Ok(serde_json::to_vec(digest)?),
+    }
+}
+
+/// Decodes a digest as stored, its signature is not verified.
+pub fn zfs_decode_digest(bs: &[u8]) -> ZfsResult<FragmentationDigest> {
+    match serde_json::from_slice::<SignedDigest>(bs) {
+        Ok(SignedDigest { payload, mut signature }) => {
+            let mut digest = serde_json::from_str::<FragmentationDigest>(&payload)?;
+            signature.payload = payload;
+            digest.signature = Some(signature);
+            Ok(digest)
+        }
+        Err(_) => Ok(serde_json::from_slice::<FragmentationDigest>(bs)?),
+    }
+}
+
+/// Generates a new ed25519 key, stores its hex encoded secret in `path` and
+/// returns the hex encoded public key.
+pub fn zfs_generate_signing_key(path: &str) -> ZfsResult<String> {
+    let key = SigningKey::generate(&mut rand_core::OsRng);
+    std::fs::write(path, hex::encode(key.to_bytes()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(hex::encode(key.verifying_key().to_bytes()))
+}
+
+pub fn zfs_read_signing_key(path: &str) -> ZfsResult<SigningKey> {
+    let s = std::fs::read_to_string(path)?;
+    let bs: [u8; 32] = hex::decode(s.trim())
+        .ok()
+        .and_then(|bs| bs.try_into().ok())
+        .ok_or_else(|| ZfsError::Config(format!("Invalid signing key in {}", path)))?;
+    Ok(SigningKey::from_bytes(&bs))
+}
+
+fn parse_public_key(s: &str) -> ZfsResult<VerifyingKey> {
+    let bs: [u8; 32] = hex::decode(s)
+        .ok()
+        .and_then(|bs| bs.try_into().ok())
+        .ok_or_else(|| ZfsError::Untrusted(format!("Invalid public key: {}", s)))?;
+    VerifyingKey::from_bytes(&bs).map_err(|e| ZfsError::Untrusted(format!("Invalid public key {}: {}", s, e)))
+}
+
+/// The signing key configured for this process, if any.
+fn signing_key() -> Option<&'static SigningKey> {
+    SIGNING_KEY
+        .get_or_init(|| {
+            zfs_config().security.signing_key.as_ref().and_then(|path| {
+                zfs_read_signing_key(path)
+                    .map_err(|e| log::error!(target: "security", "Unable to load the signing key: {}", e))
+                    .ok()
+            })
+        })
+        .as_ref()
+}
+
+/// Signs `digest` with the configured key, the digest is left unsigned when
+/// no key is configured. The digest must not be modified once signed.
+pub fn sign_digest(digest: &mut FragmentationDigest) -> ZfsResult<()> {
+    let Some(key) = signing_key() else {
+        if zfs_config().security.signing_key.is_some() {
+            return Err(ZfsError::Config("The signing key could not be loaded".into()));
+        }
+        return Ok(());
+    };
+    sign_digest_with(key, &zfs_config().security.signer, digest)
+}
+
+fn sign_digest_with(key: &SigningKey, signer: &str, digest: &mut FragmentationDigest) -> ZfsResult<()> {
+    // Without the hashes, the signature would not cover the fragments
+    if digest.fragment_hashes.len() != digest.fragments as usize {
+        return Err(ZfsError::Integrity(format!("The fragment hashes of {} are missing", &digest.name)));
+    }
+    digest.signature = None;
+    let payload = serde_json::to_string(digest)?;
+    let signature = key.sign(payload.as_bytes());
+    digest.signature = Some(DigestSignature {
+        signer: signer.to_string(),
+        public_key: hex::encode(key.verifying_key().to_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+        payload,
+    });
+    Ok(())
+}
+
+/// Checks the signature of `digest` against the trust store of the
+/// configuration. Returns the name of the signer as known by the trust store,
+/// the name recorded in the signature itself is never reported as it is
+/// chosen by whoever signed.
+///
+/// Unsigned digests are refused when signatures are required or signers are
+/// trusted. The signed digests have to record the hash of every fragment, so
+/// that the signature also covers the content of the file.
+pub fn verify_digest(digest: &FragmentationDigest) -> ZfsResult<Option<String>> {
+    verify_digest_with(&zfs_config().security, digest)
+}
+
+fn verify_digest_with(conf: &SecurityConfig, digest: &FragmentationDigest) -> ZfsResult<Option<String>> {
+    let Some(sig) = &digest.signature else {
+        if conf.require_signature || !conf.trusted_signers.is_empty() {
+            return Err(ZfsError::Untrusted(format!("The digest of {} is not signed", &digest.name)));
+        }
+        return Ok(None);
+    };
+    let public_key = parse_public_key(&sig.public_key)?;
+    let bs: [u8; 64] = hex::decode(&sig.signature)
+        .ok()
+        .and_then(|bs| bs.try_into().ok())
+        .ok_or_else(|| ZfsError::Untrusted(format!("Invalid signature for {}", &digest.name)))?;
+    public_key
+        .verify(sig.payload.as_bytes(), &Signature::from_bytes(&bs))
+        .map_err(|_| ZfsError::Untrusted(format!("Bad signature for {}", &digest.name)))?;
+    if digest.fragment_hashes.len() != digest.fragments as usize {
+        return Err(ZfsError::Untrusted(format!(
+            "The signed digest of {} does not record the hash of every fragment",
+            &digest.name
+        )));
+    }
+
+    match conf
+        .trusted_signers
+        .iter()
+        .find(|t| t.public_key.eq_ignore_ascii_case(&sig.public_key))
+    {
+        Some(trusted) => Ok(Some(trusted.name.clone())),
+        // Anyone can sign with a key of their own
+        None if conf.trusted_signers.is_empty() && !conf.require_signature => Ok(None),
+        None => Err(ZfsError::Untrusted(format!(
+            "{} is signed by {} ({}) which is not trusted",
+            &digest.name, &sig.signer, &sig.public_key
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unsigned_digest() -> FragmentationDigest {
+        FragmentationDigest {
+            name: "a/b".into(),
+            size: 2048,
+            crc: 42,
+            fragment_size: 1024,
+            fragments: 2,
+            expires: None,
+            signature: None,
+            generation: None,
+            metadata: None,
+            zero_fragments: vec![],
+            adaptive_fragment_size: false,
+            fragment_hashes: vec!["h0".into(), "h1".into()],
+        }
+    }
+
+    fn signed(key: &SigningKey) -> FragmentationDigest {
+        let mut digest = unsigned_digest();
+        sign_digest_with(key, "mallory", &mut digest).unwrap();
+        digest
+    }
+
+    fn trusting(key: &SigningKey) -> SecurityConfig {
+        SecurityConfig {
+            trusted_signers: vec![TrustedSigner {
+                name: "alice".into(),
+                public_key: hex::encode(key.verifying_key().to_bytes()),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SigningKey::generate(&mut rand_core::OsRng);
+        let digest = signed(&key);
+        // The trust store names the signer, not the signature
+        assert_eq!(verify_digest_with(&trusting(&key), &digest).unwrap(), Some("alice".into()));
+        assert_eq!(verify_digest_with(&SecurityConfig::default(), &digest).unwrap(), None);
+
+        let mut unhashed = unsigned_digest();
+        unhashed.fragment_hashes.clear();
+        assert!(sign_digest_with(&key, "alice", &mut unhashed).is_err());
+    }
+
+    #[test]
+    fn reject_tampered_digests() {
+        let key = SigningKey::generate(&mut rand_core::OsRng);
+        let conf = SecurityConfig::default();
+
+        let mut payload = signed(&key);
+        let sig = payload.signature.as_mut().unwrap();
+        sig.payload = sig.payload.replace("\"size\":2048", "\"size\":4096");
+        assert!(matches!(verify_digest_with(&conf, &payload), Err(ZfsError::Untrusted(_))));
+
+        let mut other_key = signed(&key);
+        let other = SigningKey::generate(&mut rand_core::OsRng);
+        other_key.signature.as_mut().unwrap().public_key = hex::encode(other.verifying_key().to_bytes());
+        assert!(verify_digest_with(&conf, &other_key).is_err());
+
+        let mut garbage = signed(&key);
+        garbage.signature.as_mut().unwrap().signature = "00".into();
+        assert!(verify_digest_with(&conf, &garbage).is_err());
+    }
+
+    #[test]
+    fn reject_untrusted_signers() {
+        let key = SigningKey::generate(&mut rand_core::OsRng);
+        let other = SigningKey::generate(&mut rand_core::OsRng);
+        let required = SecurityConfig { require_signature: true, ..Default::default() };
+
+        assert!(matches!(verify_digest_with(&trusting(&other), &signed(&key)), Err(ZfsError::Untrusted(_))));
+        assert!(verify_digest_with(&required, &signed(&key)).is_err());
+        assert!(verify_digest_with(&required, &unsigned_digest()).is_err());
+        assert!(verify_digest_with(&trusting(&key), &unsigned_digest()).is_err());
+        assert_eq!(verify_digest_with(&SecurityConfig::default(), &unsigned_digest()).unwrap(), None);
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let key = SigningKey::generate(&mut rand_core::OsRng);
+        let digest = signed(&key);
+        let decoded = zfs_decode_digest(&zfs_encode_digest(&digest).unwrap()).unwrap();
+        let sig = decoded.signature.as_ref().unwrap();
+        assert_eq!(sig.payload, digest.signature.as_ref().unwrap().payload);
+        assert_eq!(decoded.size, digest.size);
+        assert_eq!(verify_digest_with(&trusting(&key), &decoded).unwrap(), Some("alice".into()));
+
+        let unsigned = zfs_decode_digest(&zfs_encode_digest(&unsigned_digest()).unwrap()).unwrap();
+        assert!(unsigned.signature.is_none());
+        assert_eq!(unsigned.fragment_hashes, unsigned_digest().fragment_hashes);
+        assert!(zfs_decode_digest(b"{").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use zenoh::query::Query;
+use zenoh::Session;
+
+/// The staged digests served from, by staging directory, with the
+/// modification time of their file when they were read.
+type StagedDigests = HashMap<String, (SystemTime, Arc<FragmentationDigest>)>;
+
+/// A staged key, with its generation if any.
+type StagedKey<'a> = (&'a str, Option<&'a str>);
+
+static DIGESTS: OnceLock<Mutex<StagedDigests>> = OnceLock::new();
+
+//
+// In swarm mode, every zfsd serves the fragments it holds locally, in its
+// download and upload staging, on `@zfs-swarm/<fragment key>`. The daemons
+// downloading a file first ask their peers for its fragments and only query
+// the storages for the ones no peer has, thus a file distributed to many
+// nodes is mostly pulled from nearby peers rather than from the storages.
+//
+// The swarm uses its own key space so that peers are never mistaken for
+// storages, e.g. by zfsck.
+//
+
+pub const ZFS_SWARM_PREFIX: &str = "@zfs-swarm";
+
+/// The key under which the peers serve the fragment stored at `frag_key`.
+pub fn zfs_swarm_key(frag_key: &str) -> String {
+    format!("{}/{}", ZFS_SWARM_PREFIX, frag_key)
+}
+
+fn digests() -> std::sync::MutexGuard<'static, StagedDigests> {
+    DIGESTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Parses the storage key `frag_key`, i.e. `<base>/<key>/<generation>/<n>`
+/// or `<base>/<key>/<n>`, into the fragment number and the keys, with their
+/// generation, it may belong to.
+fn parse_frag_key<'a>(base: &str, frag_key: &'a str) -> Option<(Vec<StagedKey<'a>>, u32)> {
+    let rest = frag_key.strip_prefix(base)?.strip_prefix('/')?;
+    let (prefix, n) = rest.rsplit_once('/')?;
+    let n = n.parse::<u32>().ok()?;
+    let mut candidates = vec![(prefix, None)];
+    if let Some((key, generation)) = prefix.rsplit_once('/') {
+        candidates.push((key, Some(generation)));
+    }
+    Some((candidates, n))
+}
+
+/// The digest staged in `dir`, read again only when its file changed.
+async fn staged_digest(dir: &str) -> Option<Arc<FragmentationDigest>> {
+    let path = format!("{}/{}", dir, ZFS_DIGEST);
+    let Ok(mtime) = tokio::fs::metadata(&path).await.and_then(|m| m.modified()) else {
+        digests().remove(dir);
+        return None;
+    };
+    if let Some((_, digest)) = digests().get(dir).filter(|(t, _)| *t == mtime) {
+        return Some(digest.clone());
+    }
+    let digest = Arc::new(read_defrag_digest(dir).await.ok()?);
+    digests().insert(dir.to_string(), (mtime, digest.clone()));
+    Some(digest)
+}
+
+/// The path of the fragment staged locally for the storage key `frag_key`,
+/// if its length is the one expected by the staged digest.
+async fn staged_fragment(frag_key: &str) -> Option<String> {
+    let (candidates, n) = parse_frag_key(zfs_base_dir(), frag_key)?;
+    for (key, generation) in candidates {
+        for dir in [zfsd_download_frags_dir_for_key(key), zfsd_upload_frags_dir_for_key(key)].into_iter().flatten() {
+            let Some(digest) = staged_digest(&dir).await else {
+                continue;
+            };
+            if digest.generation.as_deref() != generation || n >= digest.fragments {
+                continue;
+            }
+            let path = format!("{}/{}", dir, n);
+            let len = std::fs::metadata(&path).map(|m| m.len()).ok();
+            if len == Some(digest.fragment_len(n)) {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/// Serves the fragments held by this zfsd to its peers.
+pub async fn serve_swarm(z: Arc<Session>) -> ZfsResult<()> {
+    let queryable = z
+        .declare_queryable(format!("{}/{}/**", ZFS_SWARM_PREFIX, zfs_base_dir()))
+        .await?;
+    while let Ok(query) = queryable.recv_async().await {
+        // Fragments are only served one by one
+        if !query.key_expr().is_wild() {
+            tokio::task::spawn(serve_fragment(query));
+        }
+    }
+    Ok(())
+}
+
+async fn serve_fragment(query: Query) {
+    let ke = query.key_expr().clone();
+    let Some(frag_key) = ke.as_str().strip_prefix(&format!("{}/", ZFS_SWARM_PREFIX)) else {
+        return;
+    };
+    if let Some(path) = staged_fragment(frag_key).await {
+        match zfs_read_payload(&path).await {
+            Ok(bs) => {
+                log::debug!(target: "swarm", "Serving {} to a peer", &path);
+                if let Err(e) = query.reply(&ke, bs).await {
+                    log::warn!(target: "swarm", "Unable to reply to {}: {}", &ke, e);
+                }
+            }
+            Err(e) => log::debug!(target: "swarm", "Unable to read {}: {}", &path, e),
+        }
+    }
+}
+
+/// Asks the peers for the fragment stored at `frag_key`, gives up after the
+/// configured swarm timeout.
+pub async fn fetch_from_peers(
+    z: &Session,
+    frag_key: &str,
+    validate: impl Fn(&[u8]) -> ZfsResult<()>,
+) -> ZfsResult<Vec<u8>> {
+    let swarm_key = zfs_swarm_key(frag_key);
+    match tokio::time::timeout(
+        zfs_config().swarm.timeout(),
+        fetch_from_replicas(z, &swarm_key, validate),
+    )
+    .await
+    {
+        Ok(r) => r,
+        Err(_) => Err(ZfsError::Timeout(format!("No peer replied for {}", frag_key))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fragment_keys() {
+        let (candidates, n) = parse_frag_key("zfs", "zfs/a/b/gen/12").unwrap();
+        assert_eq!(n, 12);
+        assert_eq!(candidates, vec![("a/b/gen", None), ("a/b", Some("gen"))]);
+        let (candidates, n) = parse_frag_key("zfs", "zfs/a/0").unwrap();
+        assert_eq!((candidates, n), (vec![("a", None)], 0));
+
+        assert!(parse_frag_key("zfs", "zfs/a/zfs-digest").is_none());
+        assert!(parse_frag_key("zfs", "zfs/a/-1").is_none());
+        assert!(parse_frag_key("zfs", "zfs/3").is_none());
+        assert!(parse_frag_key("zfs", "zfsx/a/3").is_none());
+        assert!(parse_frag_key("zfs", "other/a/3").is_none());
+        assert_eq!(zfs_swarm_key("zfs/a/3"), "@zfs-swarm/zfs/a/3");
+    }
+}
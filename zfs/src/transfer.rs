@@ -7,74 +7,173 @@ use zenoh::qos::CongestionControl;
 
 use zenoh::query::*;
 use zenoh::Session;
-pub async fn upload_fragment(z: &Session, path: &str, key: &str) {
+pub async fn upload_fragment(z: &Session, path: &str, key: &str) -> ZfsResult<()> {
     log::debug!(target: "transfer", "Uploading fragment {} for key {}", path, key);
-    let path = PathBuf::from(path);
-    let bs = std::fs::read(path.as_path()).expect(&format!("path: {} should be valid", &path.to_string_lossy()));
+    let bs = zfs_read_payload(path).await?;
     z.put(key, bs)
         .congestion_control(CongestionControl::Block)
-        .await
-        .unwrap();
+        .await?;
+    let pace = zfs_config().rate_limits.upload_pace_ms;
+    if pace > 0 {
+        tokio::time::sleep(Duration::from_millis(pace)).await;
+    }
+    Ok(())
+}
+
+/// Resumes the upload described by the digest at `path` after a zfsd restart,
+/// or once its transfer window opens.
+pub async fn resume_upload(z: Arc<Session>, path: PathBuf) -> ZfsResult<()> {
+    let job_id = zfs_job_id(&path);
+    let job = match zfs_jobs().map(|db| db.get(&job_id)) {
+        Some(r) => r?,
+        None => None,
+    };
+    let job = match job {
+        Some(job) if !job.is_active() => return Ok(()),
+        Some(job) if matches!(job.state, JobState::Transferring | JobState::Committing) => job,
+        Some(job) if job.state == JobState::Paused && job.fragments > 0 => job,
+        _ => {
+            log::info!(target: "zfsd", "Resuming fragmentation of {:?}", &path);
+            fragment_from_digest(z.clone(), path.to_string_lossy().to_string()).await?;
+            match zfs_jobs().map(|db| db.get(&job_id)) {
+                Some(Ok(Some(job))) if job.state != JobState::Failed => job,
+                _ => return Ok(()),
+            }
+        }
+    };
+    zfs_acquire_key(&job_id, JobKind::Upload, &job.key).await;
+    if !zfs_may_transfer(&job_id, &job.key, &job.windows) {
+        log::info!(target: "zfsd", "Not resuming the upload of {} before its transfer window or a storage", &job.key);
+        zfs_update_job(&job_id, |j| j.state = JobState::Paused);
+        return Ok(());
+    }
+    let _permit = zfs_admit_job(&job_id, job.priority).await;
+    log::info!(target: "zfsd", "Resuming upload of {}", &job.key);
+    upload_job(&z, &job_id).await
 }
 
-pub async fn download_fragment(z: Arc<Session>, key: String, n: u32) -> Result<(), String> {
+/// Downloads the fragment `n` of the file described by `digest` for the job
+/// `job_id` requested by `spec`. The fragment is checked against the length
+/// expected by the digest and written atomically into the staging directory
+/// of the key.
+pub async fn download_fragment(
+    z: Arc<Session>,
+    job_id: String,
+    spec: Arc<DownloadDigest>,
+    digest: Arc<FragmentationDigest>,
+    n: u32,
+) -> ZfsResult<()> {
+    let key = &spec.key;
     log::debug!(target: "transfer", "Downloading fragment # {} for key {}", n, &key);
 
-    let path = zfsd_download_frags_dir_for_key(&key);
+    let path = zfsd_download_frags_dir_for_key(key)?;
     // let frag_key = format!("{}/{}/{}", zfs_upload_frags_key_prefix(), key, n);
-    let frag_key = zfs_nth_frag_key(&key, n);
+    let frag_key = zfs_frag_key(key, digest.generation.as_deref(), n);
     let frag = format!("{}/{}", &path, n);
-    if Path::new(&frag).exists() {
-        log::debug!(
-            "The fragment {} already has already been downloaded, skipping.",
-            &frag
-        );
+    if digest.is_zero_fragment(n) {
         return Ok(());
     }
-
+    let expected_len = digest.fragment_len(n);
     // First check if the fragment is already there -- there is potential concurrency between
     // the sanitizer and the regular download process.
-    log::debug!(target: "zfsd", "Retrieving fragment: {}/{}", key, n);
-    let replies = z
-        .get(&frag_key.clone())
-        .target(QueryTarget::DEFAULT)
-        .await.unwrap();
-
-    if let Ok(reply) = replies.recv_async().await {
-        if let Ok(r) = reply.result() {
-            let bs = r.payload().to_bytes();
+    match std::fs::metadata(&frag) {
+        Ok(m) if m.len() == expected_len => {
+            log::debug!(
+                "The fragment {} already has already been downloaded, skipping.",
+                &frag
+            );
+            return Ok(());
+        }
+        Ok(m) => log::info!(
+            "The fragment {} is {} bytes long instead of {}, downloading it again.",
+            &frag,
+            m.len(),
+            expected_len
+        ),
+        Err(_) => (),
+    }
+    if cache_lookup(&digest, n, &frag).await {
+        log::debug!(target: "transfer", "The fragment {} was found in the cache", &frag);
+        return Ok(());
+    }
 
-        tokio::fs::write(std::path::Path::new(&frag), bs)
+    zfs_wait_transfer(&job_id, key, &spec.windows, None).await;
+    let _permit = zfs_admit_fragment(&job_id, spec.priority).await;
+    zfs_throttle(&job_id, key, &spec.windows, expected_len).await;
+    log::debug!(target: "zfsd", "Retrieving fragment: {}/{}", key, n);
+    let validate = |bs: &[u8]| digest.check_fragment(n, bs);
+    let from_peers = if zfs_config().swarm.enabled && zfs_peers_alive() {
+        fetch_from_peers(&z, &frag_key, validate)
             .await
-            .map_err(|e| format!("{:?}", e))
-        } else {
-            Err(format!("Unable to retrieve fragment: {}", &frag_key))
-        }
+            .map_err(|e| log::debug!(target: "transfer", "Fetching {} from the storages: {}", &frag_key, e))
+            .ok()
     } else {
-        Err(format!("Unable to retrieve fragment: {}", &frag_key))
+        None
+    };
+    let bs = match from_peers {
+        Some(bs) => bs,
+        None => fetch_from_replicas(&z, &frag_key, validate).await?,
+    };
+    write_atomically(&frag, &bs).await?;
+    cache_insert(&digest, n, &frag).await;
+    Ok(())
+}
+
+/// Makes sure that the download staging directory of `key` holds `digest`,
+/// the fragments staged for another generation of the file are discarded.
+pub async fn prepare_download_staging(key: &str, digest: &FragmentationDigest) -> ZfsResult<()> {
+    let frags_dir = zfsd_download_frags_dir_for_key(key)?;
+    match read_defrag_digest(&frags_dir).await {
+        Ok(local) if local.generation == digest.generation && local.crc == digest.crc => Ok(()),
+        r => {
+            if r.is_ok() {
+                log::info!("{} has been replaced, restarting its download", key);
+                let _ignore = std::fs::remove_dir_all(&frags_dir);
+            }
+            tokio::fs::create_dir_all(std::path::Path::new(&frags_dir)).await?;
+            write_defrag_digest(digest, &frags_dir).await
+        }
     }
 }
+
+/// Retrieves and verifies the fragmentation digest stored at `digest_key`.
 pub async fn download_fragmentation_digest(
     z: std::sync::Arc<Session>,
     digest_key: &str,
-) -> Result<FragmentationDigest, String> {
+) -> ZfsResult<FragmentationDigest> {
+    let digest = get_fragmentation_digest(&z, digest_key).await?;
+    verify_digest(&digest)?;
+    Ok(digest)
+}
+
+/// Retrieves the fragmentation digest stored at `digest_key`, without
+/// verifying its signature.
+pub async fn get_fragmentation_digest(
+    z: &Session,
+    digest_key: &str,
+) -> ZfsResult<FragmentationDigest> {
     log::debug!(target: "zfsd", "Retrieving fragmentation digest: {}", &digest_key);
     let replies = z
         .get(digest_key)
         .target(QueryTarget::DEFAULT)
-        .await
-        .unwrap();
-
-    if let Ok(reply) = replies.recv_async().await {
-        if let Ok(r) = reply.result() {
-            let bs = r.payload().to_bytes();
-            let digest = serde_json::from_slice::<FragmentationDigest>(&bs).unwrap();
-            Ok(digest)
-        } else {
-            Err("Unable to retriefe manifest".to_string())
-        }
-    } else {
-        Err("Unable to retriefe manifest".to_string())
+        .await?;
+
+    match replies.recv_async().await {
+        Ok(reply) => match reply.result() {
+            Ok(r) => {
+                let bs = r.payload().to_bytes();
+                zfs_decode_digest(&bs)
+            }
+            Err(e) => Err(ZfsError::NotFound(format!(
+                "Unable to retrieve manifest {}: {}",
+                digest_key,
+                e.payload().try_to_string().unwrap_or_default()
+            ))),
+        },
+        Err(_) => Err(ZfsError::NotFound(format!(
+            "No reply for manifest: {}",
+            digest_key
+        ))),
     }
 }
 
@@ -82,30 +181,101 @@ pub async fn download_fragmentation_digest(
 pub async fn download(
     z: std::sync::Arc<Session>,
     path_buf: PathBuf
-) -> Result<(), String> {
-    let bs = std::fs::read(path_buf.as_path()).unwrap();
-    let download_spec = match serde_json::from_slice::<DownloadDigest>(&bs) {
-        Ok(ds) => ds,
-        Err(e) => return Err(format!("{:?}", e)),
-    };
+) -> ZfsResult<()> {
+    let bs = tokio::fs::read(path_buf.as_path()).await?;
+    let download_spec = Arc::new(serde_json::from_slice::<DownloadDigest>(&bs)?);
+    let job_id = zfs_job_id(&path_buf);
+    let mut job = Job::new(&job_id, JobKind::Download, &download_spec.key, &download_spec.path);
+    job.priority = download_spec.priority;
+    job.windows = download_spec.windows.clone();
+    job.hooks = download_spec.hooks.clone();
+    if zfs_follow_download(&job_id, &download_spec.key) {
+        // The leading download reassembles the file for this job too
+        job.state = JobState::Transferring;
+        zfs_put_job(&job);
+        return Ok(());
+    }
+    zfs_put_job(&job);
+    zfs_acquire_key(&job_id, JobKind::Download, &download_spec.key).await;
+    zfs_wait_transfer(&job_id, &download_spec.key, &download_spec.windows, None).await;
+    let mut permit = zfs_admit_job(&job_id, download_spec.priority).await;
+    zfs_update_job(&job_id, |j| j.state = JobState::Transferring);
 
-    if std::path::Path::new(&download_spec.path).exists() {
-        println!(
-            "The file {} has already been downloaded.",
-            &download_spec.path
-        );
+    let r = download_spec_fragments(z, &job_id, &download_spec, &mut permit).await;
+    if r.is_ok() {
+        complete_followers(&job_id, &download_spec.key).await;
+    }
+    zfs_update_job(&job_id, |j| match &r {
+        Ok(()) => j.state = JobState::Completed,
+        // The sanitizer keeps on retrying the other errors
+        Err(e @ (ZfsError::Integrity(_) | ZfsError::Invalid(_) | ZfsError::Untrusted(_))) => {
+            j.state = JobState::Failed;
+            j.error = Some(e.to_string());
+        }
+        Err(e) => j.error = Some(e.to_string()),
+    });
+    r
+}
+
+/// Reassembles the file downloaded by the job `leader_id` into the paths of
+/// the downloads following it. The failures are left to the sanitizer.
+async fn complete_followers(leader_id: &str, key: &str) {
+    let leader = zfs_jobs().and_then(|db| db.get(leader_id).ok().flatten());
+    for job_id in zfs_take_followers(key) {
+        let r = async {
+            let digest_path = zfsd_download_digest_path(&job_id)?;
+            let spec = zfs_read_download_digest_from(Path::new(&digest_path)).await?;
+            if !spec.replace && Path::new(&spec.path).exists() {
+                return Ok(true);
+            }
+            log::info!(target: "zfsd", "Reassembling {} into {} for job {}", key, &spec.path, &job_id);
+            defragment(key, &spec.path, spec.preserve).await
+        }
+        .await;
+        zfs_update_job(&job_id, |j| {
+            if let Some(leader) = &leader {
+                j.size = leader.size;
+                j.fragments = leader.fragments;
+                j.signer = leader.signer.clone();
+            }
+            match &r {
+                Ok(true) => {
+                    j.state = JobState::Completed;
+                    j.transferred = j.fragments;
+                }
+                Ok(false) => {
+                    j.state = JobState::Failed;
+                    j.error = Some(format!("crc mismatch for {}", key));
+                }
+                Err(e) => j.error = Some(e.to_string()),
+            }
+        });
+    }
+}
+
+async fn download_spec_fragments(
+    z: std::sync::Arc<Session>,
+    job_id: &str,
+    download_spec: &Arc<DownloadDigest>,
+    permit: &mut JobPermit,
+) -> ZfsResult<()> {
+    if !download_spec.replace && std::path::Path::new(&download_spec.path).exists() {
+        log::info!(target: "transfer", "The file {} has already been downloaded.", &download_spec.path);
         return Ok(());
     }
 
     // let frag_digest = format!("{}/{}/{}", zfs_upload_frags_key_prefix(), download_spec.key, ZFS_DIGEST);
     let frag_digest= zfs_frags_digest_for_key(&download_spec.key);
     log::debug!(target: "tranfer", "Get Frag Digest: {}", &frag_digest);
-    let digest = download_fragmentation_digest(z.clone(), &frag_digest).await?;
+    let digest = get_fragmentation_digest(&z, &frag_digest).await?;
+    let signer = verify_digest(&digest)?;
+    zfs_update_job(job_id, |j| {
+        j.size = digest.size;
+        j.fragments = digest.fragments;
+        j.signer = signer.clone();
+    });
 
-    let frags_dir = zfsd_download_frags_dir_for_key(&download_spec.key);
-    tokio::fs::create_dir_all(std::path::Path::new(&frags_dir))
-        .await
-        .unwrap();
+    prepare_download_staging(&download_spec.key, &digest).await?;
 
     let bar = ProgressBar::new(digest.fragments.into());
     bar.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {frag}/{total_frags} ({eta})")
@@ -113,34 +283,47 @@ pub async fn download(
         .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
         .progress_chars("#>-"));
 
-    write_defrag_digest(&digest, &frags_dir).await?;
+    let pace = if download_spec.pace > 0 {
+        Duration::from_millis(download_spec.pace as u64)
+    } else {
+        Duration::from_millis(zfs_config().rate_limits.download_pace_ms)
+    };
+    let digest = Arc::new(digest);
     for i in 0..digest.fragments {
-        download_fragment(z.clone(), download_spec.key.clone(), i).await?;
+        if !digest.is_zero_fragment(i) {
+            zfs_wait_transfer(job_id, &download_spec.key, &download_spec.windows, Some(&mut *permit)).await;
+            download_fragment(z.clone(), job_id.to_string(), download_spec.clone(), digest.clone(), i).await?;
+        }
+        zfs_update_job(job_id, |j| j.transferred = i + 1);
         bar.inc(1);
+        if !pace.is_zero() {
+            tokio::time::sleep(pace).await;
+        }
     }
 
     log::debug!(target: "zfsd", "Degragmenting into {}", &download_spec.path);
     let p = std::path::Path::new(&download_spec.path);
     match p.parent() {
         Some(parent) => {
-            std::fs::create_dir_all(parent).unwrap();
-            defragment(&download_spec.key, &download_spec.path)
-                .await
-                .map(|r| {
-                    if r {
-                        bar.finish();
-                    } else {
-                        log::warn!(
-                            "The file received for {} was currupted.",
-                            &download_spec.key
-                        )
-                    }
-                })
+            tokio::fs::create_dir_all(parent).await?;
+            if defragment(&download_spec.key, &download_spec.path, download_spec.preserve).await? {
+                bar.finish();
+                Ok(())
+            } else {
+                log::warn!(
+                    "The file received for {} was currupted.",
+                    &download_spec.key
+                );
+                Err(ZfsError::Integrity(format!(
+                    "crc mismatch for {}",
+                    &download_spec.key
+                )))
+            }
         }
         None => {
             log::warn!(target: "zfsd", "Invalid target path: {:?}\n Unable to defragment", p);
             bar.finish_with_message("failed to defragment (see log)");
-            Ok(())
+            Err(ZfsError::Invalid(format!("Invalid target path: {:?}", p)))
         }
     }
 }
@@ -0,0 +1,357 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::time::Instant;
+use zenoh::key_expr::KeyExpr;
+use zenoh::Session;
+
+static LIMITERS: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+
+//
+// The transfers of a key can be restricted to windows of the day, in local
+// time, e.g. `22:00-06:00`, optionally with a rate limit in bits per second
+// within the window, e.g. `08:00-18:00@10M`. The windows come from the job,
+// see `zut -w` and `zet -w`, or else from the first configured schedule whose
+// prefix includes the key.
+//
+// While the window of a job is closed, or no storage is reachable, the job is
+// `Paused`:
+//
+//  - a download waits, without holding a slot of the scheduler, and the
+//    sanitizer leaves it alone;
+//  - an upload keeps its fragments staged, they are uploaded once the window
+//    opens again.
+//
+// The rate limit of a configured schedule is shared by all the transfers
+// under its prefix.
+//
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+///
+/// A window of the day, in minutes since midnight local time, during which
+/// transfers are allowed at up to `max_bps` bits per second (0 means
+/// unlimited). A window whose end is before its start spans midnight.
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TransferWindow {
+    pub start: u32,
+    pub end: u32,
+    pub max_bps: u64,
+}
+
+impl TransferWindow {
+    /// True when the window includes the minute of the day `minute`.
+    pub fn includes(&self, minute: u32) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Less => self.start <= minute && minute < self.end,
+            std::cmp::Ordering::Greater => minute >= self.start || minute < self.end,
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+fn parse_minute(s: &str) -> Option<u32> {
+    let (h, m) = s.trim().split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    (h <= 24 && m < 60 && h * 60 + m <= MINUTES_PER_DAY).then_some((h * 60 + m) % MINUTES_PER_DAY)
+}
+
+fn parse_rate(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (n, factor) = match s.strip_suffix(['k', 'K']) {
+        Some(n) => (n, 1_000),
+        None => match s.strip_suffix('M') {
+            Some(n) => (n, 1_000_000),
+            None => match s.strip_suffix('G') {
+                Some(n) => (n, 1_000_000_000),
+                None => (s, 1),
+            },
+        },
+    };
+    n.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+impl std::str::FromStr for TransferWindow {
+    type Err = ZfsError;
+
+    /// Parses `HH:MM-HH:MM[@RATE]`, the rate being in bits per second with an
+    /// optional `k`, `M` or `G` suffix.
+    fn from_str(s: &str) -> ZfsResult<TransferWindow> {
+        let invalid = || ZfsError::Invalid(format!("Invalid transfer window: {}", s));
+        let (range, rate) = match s.split_once('@') {
+            Some((range, rate)) => (range, parse_rate(rate).ok_or_else(invalid)?),
+            None => (s, 0),
+        };
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        Ok(TransferWindow {
+            start: parse_minute(start).ok_or_else(invalid)?,
+            end: parse_minute(end).ok_or_else(invalid)?,
+            max_bps: rate,
+        })
+    }
+}
+
+impl TryFrom<String> for TransferWindow {
+    type Error = ZfsError;
+
+    fn try_from(s: String) -> ZfsResult<TransferWindow> {
+        s.parse()
+    }
+}
+
+impl From<TransferWindow> for String {
+    fn from(w: TransferWindow) -> String {
+        let mut s = format!("{:02}:{:02}-{:02}:{:02}", w.start / 60, w.start % 60, w.end / 60, w.end % 60);
+        if w.max_bps > 0 {
+            s.push_str(&format!("@{}", w.max_bps));
+        }
+        s
+    }
+}
+
+/// The current minute of the day and second of the minute, in local time.
+#[cfg(unix)]
+fn local_time() -> (u32, u32) {
+    let now = zfs_now() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        let secs = (now as u64 % (24 * 3600)) as u32;
+        return (secs / 60, secs % 60);
+    }
+    ((tm.tm_hour * 60 + tm.tm_min) as u32, tm.tm_sec as u32)
+}
+
+#[cfg(not(unix))]
+fn local_time() -> (u32, u32) {
+    let secs = (zfs_now() % (24 * 3600)) as u32;
+    (secs / 60, secs % 60)
+}
+
+/// The windows that apply to the transfers of `key`, with the identifier of
+/// their rate limiter: the ones of the job `job_id` if any, or else the ones
+/// of the first configured schedule whose prefix includes the key.
+fn windows_for<'a>(job_id: &str, key: &str, job_windows: &'a [TransferWindow]) -> Option<(String, &'a [TransferWindow])> {
+    if !job_windows.is_empty() {
+        return Some((job_id.to_string(), job_windows));
+    }
+    let zkey = KeyExpr::try_from(zfs_key(key)).ok()?;
+    zfs_config()
+        .schedules
+        .iter()
+        .find(|s| KeyExpr::try_from(s.prefix.as_str()).is_ok_and(|ke| ke.includes(&zkey)))
+        .map(|s| (s.prefix.clone(), s.windows.as_slice()))
+}
+
+///
+/// Whether the transfers of a key are currently allowed.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowState {
+    /// Allowed, at up to the given bits per second (0 means unlimited),
+    /// shared through the given rate limiter.
+    Open(String, u64),
+    /// Not allowed for at least the given duration.
+    Closed(Duration),
+}
+
+/// Whether the transfers of `key` for the job `job_id`, whose own windows are
+/// `job_windows`, are currently allowed.
+pub fn zfs_window_state(job_id: &str, key: &str, job_windows: &[TransferWindow]) -> WindowState {
+    let Some((limiter, windows)) = windows_for(job_id, key, job_windows) else {
+        return WindowState::Open(String::new(), 0);
+    };
+    let (minute, second) = local_time();
+    if let Some(w) = windows.iter().find(|w| w.includes(minute)) {
+        return WindowState::Open(format!("{}#{}", limiter, String::from(*w)), w.max_bps);
+    }
+    let wait = windows
+        .iter()
+        .map(|w| (w.start + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY)
+        .min()
+        .unwrap_or(0);
+    WindowState::Closed(Duration::from_secs((wait as u64 * 60).saturating_sub(second as u64).max(1)))
+}
+
+/// True when the job `job_id` may transfer `key` now, i.e. its transfer
+/// window is open and a storage is reachable.
+pub fn zfs_may_transfer(job_id: &str, key: &str, job_windows: &[TransferWindow]) -> bool {
+    matches!(zfs_window_state(job_id, key, job_windows), WindowState::Open(..)) && zfs_storage_available()
+}
+
+/// Waits until the transfer window of `key` is open and a storage is
+/// reachable. Meanwhile the job is shown as `Paused`, and gives its slot back
+/// to the scheduler when it holds one in `permit`.
+pub async fn zfs_wait_transfer(
+    job_id: &str,
+    key: &str,
+    job_windows: &[TransferWindow],
+    mut permit: Option<&mut JobPermit>,
+) {
+    let mut paused = false;
+    loop {
+        let window = match zfs_window_state(job_id, key, job_windows) {
+            WindowState::Closed(wait) => Some(wait),
+            WindowState::Open(..) if !zfs_storage_available() => None,
+            WindowState::Open(..) => break,
+        };
+        if !paused {
+            match window {
+                Some(wait) => log::info!(target: "window", "Pausing job {} on {} for {}s, until its transfer window opens", job_id, key, wait.as_secs()),
+                None => log::info!(target: "window", "Pausing job {} on {} until a storage is reachable", job_id, key),
+            }
+            zfs_update_job(job_id, |j| j.state = JobState::Paused);
+            if let Some(permit) = permit.as_mut() {
+                permit.release();
+            }
+            paused = true;
+        }
+        match window {
+            // The clock may change meanwhile, e.g. on DST
+            Some(wait) => tokio::time::sleep(wait.min(Duration::from_secs(60))).await,
+            None => zfs_wait_storage().await,
+        }
+    }
+    if paused {
+        if let Some(permit) = permit.as_mut() {
+            permit.readmit().await;
+        }
+        log::info!(target: "window", "Resuming job {} on {}", job_id, key);
+        zfs_update_job(job_id, |j| {
+            if j.state == JobState::Paused {
+                j.state = JobState::Transferring;
+            }
+        });
+    }
+}
+
+fn limiters() -> std::sync::MutexGuard<'static, HashMap<String, Instant>> {
+    LIMITERS.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Forgets the rate limiters of the windows of the job `job_id` once it ends.
+pub(crate) fn zfs_forget_limiters(job_id: &str) {
+    let prefix = format!("{}#", job_id);
+    limiters().retain(|limiter, _| !limiter.starts_with(&prefix));
+}
+
+/// Waits for the rate limit of the transfer window of `key`, if any, to allow
+/// transferring `bytes` more.
+pub async fn zfs_throttle(job_id: &str, key: &str, job_windows: &[TransferWindow], bytes: u64) {
+    let WindowState::Open(limiter, max_bps) = zfs_window_state(job_id, key, job_windows) else {
+        return;
+    };
+    if max_bps == 0 {
+        return;
+    }
+    let start = {
+        let mut limiters = limiters();
+        let now = Instant::now();
+        let next = limiters.entry(limiter).or_insert(now);
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(bytes as f64 * 8.0 / max_bps as f64);
+        start
+    };
+    tokio::time::sleep_until(start).await;
+}
+
+/// Periodically resumes the paused uploads once their transfer window is open
+/// and a storage is reachable again.
+pub async fn paused_upload_resumer(z: Arc<Session>) {
+    let conf = zfs_config();
+    loop {
+        tokio::time::sleep(conf.sanitizer_period()).await;
+        let Some(db) = zfs_jobs() else {
+            continue;
+        };
+        for job in db.list() {
+            // Uploads still fragmenting are resumed once fragmented
+            if job.kind != JobKind::Upload || job.state != JobState::Paused || job.fragments == 0 {
+                continue;
+            }
+            if zfs_may_transfer(&job.id, &job.key, &job.windows) {
+                let Ok(path) = zfsd_upload_digest_path(&job.id).map(std::path::PathBuf::from) else {
+                    continue;
+                };
+                log::info!(target: "window", "Resuming the upload of {}", &job.key);
+                // Not resumed twice by the next cycles
+                zfs_update_job(&job.id, |j| j.state = JobState::Transferring);
+                let z = z.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = resume_upload(z, path).await {
+                        log::warn!(target: "window", "Unable to resume the upload of {}: {}", &job.key, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(s: &str) -> TransferWindow {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_window() {
+        assert_eq!(window("08:00-18:30"), TransferWindow { start: 480, end: 1110, max_bps: 0 });
+        assert_eq!(window("22:00-06:00@10M"), TransferWindow { start: 1320, end: 360, max_bps: 10_000_000 });
+        assert_eq!(window("00:00-24:00@2k").end, 0);
+        assert_eq!(window("01:00-02:00@1G").max_bps, 1_000_000_000);
+        assert_eq!(window(" 01:05 - 02:00 ").start, 65);
+        for s in ["", "08:00", "08:00-", "8-18", "25:00-01:00", "08:60-09:00", "24:01-01:00", "08:00-18:00@", "08:00-18:00@10X"] {
+            assert!(s.parse::<TransferWindow>().is_err(), "{}", s);
+        }
+        let w = window("22:00-06:00@10M");
+        assert_eq!(window(&String::from(w)), w);
+    }
+
+    #[test]
+    fn reject_rate_overflows() {
+        assert_eq!(parse_rate("18446744073709551615"), Some(u64::MAX));
+        assert_eq!(parse_rate("18446744073G"), Some(18_446_744_073_000_000_000));
+        assert_eq!(parse_rate("18446744074G"), None);
+        assert_eq!(parse_rate("18446744073709552k"), None);
+        assert!("08:00-18:00@99999999999999M".parse::<TransferWindow>().is_err());
+    }
+
+    #[test]
+    fn forget_the_limiters_of_ended_jobs() {
+        let now = Instant::now();
+        for limiter in ["job-1#08:00-09:00@1M", "job-1#10:00-11:00@1M", "job-10#08:00-09:00@1M", "zfs/**#08:00-09:00@1M"] {
+            limiters().insert(limiter.to_string(), now);
+        }
+        zfs_forget_limiters("job-1");
+        let limiter = |l: &str| limiters().contains_key(l);
+        assert!(!limiter("job-1#08:00-09:00@1M") && !limiter("job-1#10:00-11:00@1M"));
+        assert!(limiter("job-10#08:00-09:00@1M") && limiter("zfs/**#08:00-09:00@1M"));
+        zfs_forget_limiters("job-10");
+        assert!(!limiter("job-10#08:00-09:00@1M"));
+        limiters().remove("zfs/**#08:00-09:00@1M");
+    }
+
+    #[test]
+    fn includes() {
+        let day = window("08:00-18:00");
+        assert!(!day.includes(479));
+        assert!(day.includes(480));
+        assert!(day.includes(1079));
+        assert!(!day.includes(1080));
+
+        let night = window("22:00-06:00");
+        assert!(night.includes(1320));
+        assert!(night.includes(MINUTES_PER_DAY - 1));
+        assert!(night.includes(0));
+        assert!(night.includes(359));
+        assert!(!night.includes(360));
+        assert!(!night.includes(1319));
+        assert!(!night.includes(720));
+
+        let always = window("00:00-24:00");
+        assert!(always.includes(0) && always.includes(720) && always.includes(MINUTES_PER_DAY - 1));
+    }
+}
@@ -1,44 +1,166 @@
 use clap::{App, Arg};
-use zfs::{zfsd_download_digest_dir, DownloadDigest};
+use zfs::{zfsd_download_digest_dir, zfsd_follow_digest_dir, DownloadDigest, FollowDigest, Hook, PreserveFlags, TransferWindow, ZfsResult};
 
-fn write_download_digest(digest: DownloadDigest) -> std::io::Result<()> {
+enum Request {
+    Download(DownloadDigest),
+    Follow(FollowDigest),
+    Unfollow(String),
+}
+
+fn write_digest<T: serde::Serialize>(dir: &str, digest: &T) -> ZfsResult<()> {
     let uid = uuid::Uuid::new_v4();
-    let fname = format!("{}/{}", zfsd_download_digest_dir(), uid);
-    if let Ok(bs) = serde_json::to_vec(&digest) {
+    let fname = format!("{}/{}", dir, uid);
+    if let Ok(bs) = serde_json::to_vec(digest) {
         std::fs::write(&fname, &bs)?;
     } else {
-        println!("Failed to serialise DownloadDigest -- aborting.")
+        println!("Failed to serialise the digest -- aborting.")
     }
     Ok(())
 }
-fn parse_args() -> (String, String, usize) {
+
+/// Removes the subscriptions to `key_expr`, zfsd stops them.
+fn unfollow(key_expr: &str) -> ZfsResult<usize> {
+    let mut n = 0;
+    for entry in std::fs::read_dir(zfsd_follow_digest_dir()?)?.flatten() {
+        let spec = std::fs::read(entry.path())
+            .ok()
+            .and_then(|bs| serde_json::from_slice::<FollowDigest>(&bs).ok());
+        if spec.is_some_and(|s| s.key_expr == key_expr) {
+            std::fs::remove_file(entry.path())?;
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+fn parse_args() -> Request {
     let args = App::new("zet: zfs utility to download files.")
         .arg(
-            Arg::from_usage("-p, --path[PATH]...  'The path to download the file to.'")
-                .required(true),
+            Arg::from_usage("-p, --path[PATH]...  'The path to download the file to, or the directory mirroring the followed keys.'")
+                .required_unless("unfollow"),
         )
         .arg(
             Arg::from_usage(
                 "-k, --key=[KEY]...  'The key of the file to download.'",
             )
-            .required(true),
+            .required_unless_one(&["follow", "unfollow"]),
+        )
+        .arg(
+            Arg::from_usage(
+                "--follow=[KEY_EXPR] 'Downloads every file committed under KEY_EXPR, e.g. datasets/cam1/**, until unfollowed.'",
+            )
+            .conflicts_with_all(&["key", "unfollow"])
+        )
+        .arg(
+            Arg::from_usage(
+                "--unfollow=[KEY_EXPR] 'Stops following KEY_EXPR.'",
+            )
+            .conflicts_with("key")
         )
         .arg(
             Arg::from_usage(
                 "-t, --tempo=[MSEC]...  'The time in msec that should be waited before downloading the next fragment (0 means as fast as possible).'",
             ).default_value("0"),
         )
+        .arg(
+            Arg::from_usage(
+                "-P, --preserve=[ATTRS]... 'The recorded metadata to restore: mode, timestamps, ownership, xattr or all, and setid to also restore the setuid and setgid bits and the xattrs outside of the user namespace.'",
+            )
+            .use_delimiter(true)
+        )
+        .arg(
+            Arg::from_usage(
+                "--priority=[PRIORITY] 'The priority of the download over the other jobs.'",
+            )
+            .possible_values(&["low", "normal", "high"])
+            .default_value("normal")
+        )
+        .arg(
+            Arg::from_usage(
+                "-w, --window=[WINDOW]... 'The windows of the day, in local time, during which the file is downloaded, e.g. 22:00-06:00 or 08:00-18:00@10M (bits/s).'",
+            )
+            .use_delimiter(true)
+        )
+        .arg(
+            Arg::from_usage(
+                "--hook=[HOOK]... 'A command, or a local http:// url to POST to, run once the download (of each file with --follow) completes or fails.'",
+            )
+            .number_of_values(1)
+        )
         .get_matches();
 
-    (
-        args.value_of("path").unwrap().to_string(),
-        args.value_of("key").unwrap().to_string(),
-        args.value_of("tempo").unwrap().parse().unwrap(),
-    )
+    let preserve = PreserveFlags::parse(args.values_of("preserve").into_iter().flatten()).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(-1)
+    });
+
+    let windows = args
+        .values_of("window")
+        .into_iter()
+        .flatten()
+        .map(|w| w.parse::<TransferWindow>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(-1)
+        });
+
+    let hooks = args
+        .values_of("hook")
+        .into_iter()
+        .flatten()
+        .map(|h| h.parse::<Hook>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(-1)
+        });
+
+    if let Some(key_expr) = args.value_of("unfollow") {
+        return Request::Unfollow(key_expr.to_string());
+    }
+    let path = args.value_of("path").unwrap().to_string();
+    let priority = args.value_of("priority").unwrap().parse().unwrap();
+    match args.value_of("follow") {
+        Some(key_expr) => Request::Follow(FollowDigest {
+            key_expr: key_expr.to_string(),
+            path,
+            preserve,
+            priority,
+            windows,
+            hooks,
+        }),
+        None => Request::Download(DownloadDigest {
+            path,
+            key: args.value_of("key").unwrap().to_string(),
+            pace: args.value_of("tempo").unwrap().parse().unwrap(),
+            preserve,
+            priority,
+            windows,
+            hooks,
+            replace: false,
+        }),
+    }
 }
 
 fn main() {
-    let (path, key, pace) = parse_args();
-    let digest = DownloadDigest { path, key, pace };
-    write_download_digest(digest).unwrap();
+    match parse_args() {
+        Request::Download(digest) => {
+            if let Err(e) = zfsd_download_digest_dir().and_then(|dir| write_digest(&dir, &digest)) {
+                println!("Unable to request the download: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        Request::Follow(digest) => {
+            if let Err(e) = zfsd_follow_digest_dir().and_then(|dir| write_digest(&dir, &digest)) {
+                println!("Unable to request the subscription: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        Request::Unfollow(key_expr) => match unfollow(&key_expr) {
+            Ok(0) => println!("{} is not followed.", key_expr),
+            Ok(_) => (),
+            Err(e) => println!("Unable to unfollow {}: {}", key_expr, e),
+        },
+    }
 }
@@ -0,0 +1,116 @@
+use clap::{App, Arg};
+use zfs::*;
+
+fn parse_args() -> (zenoh::Config, Vec<String>, usize, bool, bool) {
+    let args = App::new("zfsck: zfs utility to check and repair the replication and the integrity of the stored files.")
+        .arg(Arg::from_usage(
+            "-c, --config=[FILE]  'A zfsd configuration file, its storage prefix, replicas and zenoh configuration are used.'",
+        ))
+        .arg(Arg::from_usage(
+            "-k, --key=[KEY]...  'The keys to check (all the stored keys by default).'",
+        ))
+        .arg(Arg::from_usage(
+            "-t, --target=[N]  'The number of replicas every file should be stored on (0 means all the replicas seen).'",
+        ))
+        .arg(Arg::from_usage(
+            "-r, --repair  'Puts again the parts of the files stored on less than the target, or the corrupt ones with --scrub.'",
+        ))
+        .arg(Arg::from_usage(
+            "-s, --scrub  'Checks every stored fragment on every replica against its hash instead, at up to scrub.max_bps.'",
+        ))
+        .get_matches();
+
+    let config = args
+        .value_of("config")
+        .map_or_else(ZfsdConfig::default, |conf_file| {
+            ZfsdConfig::from_file(conf_file).unwrap_or_else(|e| {
+                println!("Unable to load {}: {}", conf_file, e);
+                std::process::exit(-1);
+            })
+        });
+    let target = match args.value_of("target") {
+        Some(t) => t.parse().unwrap_or_else(|_| {
+            println!("Invalid target: {}", t);
+            std::process::exit(-1);
+        }),
+        None => config.replicas.target,
+    };
+    let keys = args
+        .values_of("key")
+        .map_or_else(Vec::new, |ks| ks.map(|k| k.to_string()).collect());
+    let zconfig = config.zenoh_config().unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(-1);
+    });
+    zfs_set_config(config).unwrap();
+    (zconfig, keys, target, args.is_present("repair"), args.is_present("scrub"))
+}
+
+async fn scrub(z: &zenoh::Session, keys: &[String], repair: bool) {
+    let mut reports = zfs_scrub(z, keys, zfs_config().scrub.max_bps, repair).await.unwrap_or_else(|e| {
+        println!("Unable to scrub the stored files: {}", e);
+        std::process::exit(-1);
+    });
+    reports.sort_by(|a, b| a.key.cmp(&b.key));
+
+    println!("{:>9} {:>7} {:>8} {:>13}  KEY", "FRAGMENTS", "CORRUPT", "REPAIRED", "UNRECOVERABLE");
+    let mut failed = 0;
+    for report in &reports {
+        let unhashed = if report.hashed { "" } else { " (no hashes, length only)" };
+        println!(
+            "{:>9} {:>7} {:>8} {:>13}  {}{}",
+            report.fragments,
+            report.corrupt.len(),
+            report.repaired.len(),
+            report.unrecoverable.len(),
+            report.key,
+            unhashed
+        );
+        if report.corrupt.len() > report.repaired.len() {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        if !repair {
+            println!("{} keys have corrupt fragments, use --repair to repair them", failed);
+        }
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let (config, keys, target, repair, scrubbing) = parse_args();
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Warn)
+        .format_timestamp(None)
+        .init();
+    let z = zenoh::open(config).await.unwrap();
+    if scrubbing {
+        scrub(&z, &keys, repair).await;
+        return;
+    }
+    let mut reports = zfs_check(&z, &keys, target, repair).await.unwrap_or_else(|e| {
+        println!("Unable to check the replication: {}", e);
+        std::process::exit(-1);
+    });
+    reports.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let replicas: std::collections::BTreeSet<String> = reports.iter().flat_map(|r| r.replicas()).collect();
+    let target = if target == 0 { replicas.len() } else { target };
+    println!("{} replicas seen, target replication is {}", replicas.len(), target);
+    println!("{:>12} {:>16}  KEY", "REPLICATION", "UNDER-REPLICATED");
+    let mut failed = 0;
+    for report in &reports {
+        let under = report.under_replicated(target).len()
+            + usize::from(report.digest_replicas.len() < target);
+        if under > 0 {
+            failed += 1;
+        }
+        println!("{:>12} {:>16}  {}", report.replication(), under, report.key);
+    }
+    if failed > 0 && !repair {
+        println!("{} keys are under-replicated, use --repair to repair them", failed);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,56 @@
+use clap::{App, Arg};
+use zfs::*;
+
+fn parse_args() -> zenoh::Config {
+    let args = App::new("zls: zfs utility to list the stored files.")
+        .arg(Arg::from_usage(
+            "-c, --config=[FILE]  'A zfsd configuration file, its storage prefix, trust store and zenoh configuration are used.'",
+        ))
+        .get_matches();
+
+    let config = args
+        .value_of("config")
+        .map_or_else(ZfsdConfig::default, |conf_file| {
+            ZfsdConfig::from_file(conf_file).unwrap_or_else(|e| {
+                println!("Unable to load {}: {}", conf_file, e);
+                std::process::exit(-1);
+            })
+        });
+    let zconfig = config.zenoh_config().unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(-1);
+    });
+    zfs_set_config(config).unwrap();
+    zconfig
+}
+
+#[tokio::main]
+async fn main() {
+    let config = parse_args();
+    let z = zenoh::open(config).await.unwrap();
+    let mut digests = list_stored_digests(&z).await.unwrap();
+    digests.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    println!(
+        "{:>14} {:>10} {:>12} {:<24}  KEY",
+        "SIZE", "FRAGMENTS", "EXPIRES", "SIGNER"
+    );
+    for (key, digest) in digests {
+        let signer = match verify_digest(&digest) {
+            Ok(Some(name)) => name,
+            Ok(None) => "-".into(),
+            Err(_) => format!(
+                "{} (untrusted)",
+                digest.signature.as_ref().map_or("unsigned", |s| s.signer.as_str())
+            ),
+        };
+        println!(
+            "{:>14} {:>10} {:>12} {:<24}  {}",
+            digest.size,
+            digest.fragments,
+            digest.expires.map_or("-".into(), |t| t.to_string()),
+            signer,
+            key
+        );
+    }
+}
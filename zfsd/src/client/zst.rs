@@ -0,0 +1,70 @@
+use clap::{App, Arg};
+use zfs::{Job, ZFSD_STATUS_PREFIX};
+
+fn parse_args() -> (zenoh::Config, Option<String>, bool) {
+    let args = App::new("zst: zfs utility to show the status of zfsd jobs.")
+        .arg(Arg::from_usage(
+            "-c, --config=[FILE]  'A zenoh configuration file.'",
+        ))
+        .arg(Arg::from_usage(
+            "-k, --key=[KEY]  'Only show the jobs for this key.'",
+        ))
+        .arg(Arg::from_usage(
+            "-a, --all  'Also show the completed and failed jobs.'",
+        ))
+        .get_matches();
+
+    let config = args
+        .value_of("config")
+        .map_or_else(zenoh::Config::default, |conf_file| {
+            zenoh::Config::from_file(conf_file).unwrap_or_else(|e| {
+                println!("Unable to load {}: {}", conf_file, e);
+                std::process::exit(-1);
+            })
+        });
+    (
+        config,
+        args.value_of("key").map(|k| k.to_string()),
+        args.is_present("all"),
+    )
+}
+
+#[tokio::main]
+async fn main() {
+    let (config, key, all) = parse_args();
+    let z = zenoh::open(config).await.unwrap();
+    let replies = z
+        .get(format!("{}/*/jobs/**", ZFSD_STATUS_PREFIX))
+        .await
+        .unwrap();
+
+    let mut jobs = Vec::new();
+    while let Ok(reply) = replies.recv_async().await {
+        if let Ok(sample) = reply.result() {
+            if let Ok(job) = serde_json::from_slice::<Job>(&sample.payload().to_bytes()) {
+                jobs.push(job);
+            }
+        }
+    }
+    jobs.retain(|j| (all || j.is_active()) && key.as_ref().is_none_or(|k| &j.key == k));
+    jobs.sort_by_key(|j| j.created);
+
+    println!(
+        "{:<36} {:<8} {:<12} {:<6} {:>12} {:>15} {:<12}  KEY",
+        "ID", "KIND", "STATE", "PRIO", "SIZE", "FRAGMENTS", "SIGNER"
+    );
+    for j in jobs {
+        println!(
+            "{:<36} {:<8} {:<12} {:<6} {:>12} {:>15} {:<12}  {}{}",
+            j.id,
+            format!("{:?}", j.kind),
+            format!("{:?}", j.state),
+            format!("{:?}", j.priority),
+            j.size,
+            format!("{}/{}", j.transferred, j.fragments),
+            j.signer.as_deref().unwrap_or("-"),
+            j.key,
+            j.error.map(|e| format!(" ({})", e)).unwrap_or_default()
+        );
+    }
+}
@@ -1,16 +1,16 @@
 use clap::{App, Arg};
-use zfs::{zfsd_upload_digest_dir, UploadDigest};
+use zfs::{zfs_now, zfs_parse_date, zfs_parse_duration, zfsd_upload_digest_dir, Hook, PreserveFlags, TransferWindow, UploadDigest, ZfsResult};
 
-fn write_upload_digest(digest: UploadDigest) -> std::io::Result<()> {
+fn write_upload_digest(digest: UploadDigest) -> ZfsResult<()> {
     let uid = uuid::Uuid::new_v4();
-    let fname = format!("{}/{}", zfsd_upload_digest_dir(), uid);
+    let fname = format!("{}/{}", zfsd_upload_digest_dir()?, uid);
     if let Ok(bs) = serde_json::to_vec(&digest) {
         std::fs::write(&fname, &bs)?;
     }
     Ok(())
 }
 
-fn parse_args() -> (String, String, usize) {
+fn parse_args() -> UploadDigest {
     let args = App::new("zut: zfs utility to upload files.")
         .arg(
             Arg::from_usage("-p, --path[PATH]...  'The path for the file to upload.'")
@@ -24,23 +24,114 @@ fn parse_args() -> (String, String, usize) {
         )
         .arg(
             Arg::from_usage(
-                "-f, --fragment=[BYTES] 'The size of the fragment'",
-            ).default_value("32768")
+                "-f, --fragment=[BYTES] 'The size of the fragment, or auto to pick it from the size of the file (zfsd configured size by default)'",
+            )
+        )
+        .arg(
+            Arg::from_usage(
+                "-t, --ttl=[DURATION] 'How long the file is kept in zfs, e.g. 12h, 7d or 2w.'",
+            )
+            .conflicts_with("expires")
+        )
+        .arg(
+            Arg::from_usage(
+                "-e, --expires=[DATE] 'When the file is deleted from zfs, as YYYY-MM-DD[THH:MM:SS] (UTC).'",
+            )
+        )
+        .arg(
+            Arg::from_usage(
+                "-P, --preserve=[ATTRS]... 'The metadata to record with the file: mode, timestamps, ownership, xattr or all.'",
+            )
+            .use_delimiter(true)
+        )
+        .arg(
+            Arg::from_usage(
+                "--priority=[PRIORITY] 'The priority of the upload over the other jobs.'",
+            )
+            .possible_values(&["low", "normal", "high"])
+            .default_value("normal")
+        )
+        .arg(
+            Arg::from_usage(
+                "-w, --window=[WINDOW]... 'The windows of the day, in local time, during which the file is uploaded, e.g. 22:00-06:00 or 08:00-18:00@10M (bits/s).'",
+            )
+            .use_delimiter(true)
+        )
+        .arg(
+            Arg::from_usage(
+                "--hook=[HOOK]... 'A command, or a local http:// url to POST to, run once the upload completes or fails.'",
+            )
+            .number_of_values(1)
         )
         .get_matches();
 
-    (
-        args.value_of("path").unwrap().to_string(),
-        args.value_of("key").unwrap().to_string(),
-        args.value_of("fragment").unwrap().parse().unwrap()
-    )
+    let preserve = PreserveFlags::parse(args.values_of("preserve").into_iter().flatten()).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(-1)
+    });
+
+    let windows = args
+        .values_of("window")
+        .into_iter()
+        .flatten()
+        .map(|w| w.parse::<TransferWindow>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(-1)
+        });
+
+    let hooks = args
+        .values_of("hook")
+        .into_iter()
+        .flatten()
+        .map(|h| h.parse::<Hook>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(-1)
+        });
+
+    let expires = if let Some(ttl) = args.value_of("ttl") {
+        Some(zfs_now() + zfs_parse_duration(ttl).unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(-1)
+        }))
+    } else {
+        args.value_of("expires").map(|date| {
+            zfs_parse_date(date).unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1)
+            })
+        })
+    };
+
+    let (fragment_size, adaptive_fragment_size) = match args.value_of("fragment") {
+        Some("auto") => (0, true),
+        Some(f) => (f.parse().unwrap(), false),
+        None => (0, false),
+    };
+
+    UploadDigest {
+        path: args.value_of("path").unwrap().to_string(),
+        key: args.value_of("key").unwrap().to_string(),
+        fragment_size,
+        adaptive_fragment_size,
+        expires,
+        preserve,
+        priority: args.value_of("priority").unwrap().parse().unwrap(),
+        windows,
+        hooks,
+    }
 }
 fn main() {
-    let (path, key, fragment_size) = parse_args();
-    if std::path::Path::new(&path).exists() {
-        let digest = UploadDigest { path, key, fragment_size };
-        write_upload_digest(digest).unwrap();
+    let digest = parse_args();
+    if std::path::Path::new(&digest.path).exists() {
+        if let Err(e) = write_upload_digest(digest) {
+            println!("Unable to request the upload: {}", e);
+            std::process::exit(-1);
+        }
     } else {
-        println!("The file {} does not exit", &path);
+        println!("The file {} does not exit", &digest.path);
     }
 }
@@ -1,102 +1,187 @@
 use clap::{App, Arg};
 use futures::TryFutureExt;
-use notify::{recommended_watcher, RecursiveMode, Result, Watcher};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{recommended_watcher, RecursiveMode, Watcher};
 use std::fs::create_dir_all;
 use std::{sync::mpsc::channel};
 use std::process::exit;
 use zfs::*;
 use zenoh::config::WhatAmI;
 
-fn init() -> Result<()> {
-    create_dir_all(zfsd_upload_frags_dir())
-        .and(create_dir_all(zfsd_download_frags_dir()))
-        .and(create_dir_all(zfsd_upload_digest_dir()))
-        .and(create_dir_all(zfsd_download_digest_dir()))
-        .map_err(|e| notify::Error::generic(&format!("{:?}", e)))
+fn init() -> ZfsResult<()> {
+    for dir in [
+        zfsd_upload_frags_dir()?,
+        zfsd_download_frags_dir()?,
+        zfsd_upload_digest_dir()?,
+        zfsd_download_digest_dir()?,
+        zfsd_follow_digest_dir()?,
+        zfsd_follow_state_dir()?,
+    ] {
+        create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// The value of `r`, which zfsd cannot run without, or exits reporting `what`.
+fn or_exit<T, E: std::fmt::Display>(r: Result<T, E>, what: &str) -> T {
+    r.unwrap_or_else(|e| {
+        println!("{}: {}", what, e);
+        exit(-1);
+    })
+}
+
+/// The directory `dir`, which zfsd cannot run without, or exits.
+fn zfsd_dir(dir: ZfsResult<String>) -> std::path::PathBuf {
+    or_exit(dir.map(std::path::PathBuf::from), "zfsd has no home directory")
+}
+
+/// Resumes the uploads that were interrupted by a zfsd restart, or requested
+/// while zfsd was not running.
+fn recover_uploads(z: std::sync::Arc<zenoh::Session>) {
+    if let Ok(entries) = zfsd_dir(zfsd_upload_digest_dir()).read_dir() {
+        for entry in entries.flatten() {
+            let job = resume_upload(z.clone(), entry.path()).or_else(|e| async move {
+                log::warn!(target: "zfsd", "Failed to resume upload due to: {}", e);
+                Ok::<(), ZfsError>(())
+            });
+            tokio::task::spawn(job);
+        }
+    }
+}
+
+/// Starts the subscriptions registered with `zet --follow`, and forgets the
+/// state of the ones removed while zfsd was not running.
+fn recover_follows(z: std::sync::Arc<zenoh::Session>) {
+    let follow_digest_dir = zfsd_dir(zfsd_follow_digest_dir());
+    if let Ok(entries) = follow_digest_dir.read_dir() {
+        for entry in entries.flatten() {
+            if entry.path().is_file() && !zfs_is_tmp_path(&entry.path().to_string_lossy()) {
+                spawn_follow(z.clone(), entry.path());
+            }
+        }
+    }
+    if let Ok(entries) = zfsd_dir(zfsd_follow_state_dir()).read_dir() {
+        for entry in entries.flatten() {
+            let id = zfs_job_id(&entry.path());
+            if !follow_digest_dir.join(&id).exists() {
+                let _ignore = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+fn spawn_follow(z: std::sync::Arc<zenoh::Session>, path: std::path::PathBuf) {
+    let job = follow(z, path).or_else(|e| async move {
+        log::warn!(target: "zfsd", "Subscription failed due to: {}", e);
+        Ok::<(), ZfsError>(())
+    });
+    tokio::task::spawn(job);
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
-        .format_target(true)
-        .format_timestamp_secs()
-        .init();
-
-    log::info!(target: "zfsd", "Starting up...");
     let zconf = parse_args();
 
-    let z = std::sync::Arc::new(zenoh::open(zconf).await.unwrap());
-    init().expect("zfsd failed to initalise!");
+    let mut logger = env_logger::builder();
+    logger
+        .filter_level(zfs_config().log_level())
+        .format_target(true);
+    if zfs_config().logging.timestamps {
+        logger.format_timestamp_secs();
+    } else {
+        logger.format_timestamp(None);
+    }
+    logger.init();
+
+    log::info!(target: "zfsd", "Starting up...");
+    let z = std::sync::Arc::new(or_exit(
+        zenoh::open(zconf).await.map_err(ZfsError::from),
+        "zfsd failed to open the zenoh session",
+    ));
+    or_exit(init(), "zfsd failed to initialise");
+    or_exit(zfs_open_jobs(), "zfsd failed to open the job database");
     let (tx, rx) = channel();
-    let mut watcher = recommended_watcher(tx).unwrap();
-
-    watcher
-        .watch(
-            std::path::Path::new(&zfsd_download_digest_dir()),
-            RecursiveMode::NonRecursive)
-        .unwrap();
-    watcher
-        .watch(
-            std::path::Path::new(&zfsd_upload_digest_dir()),
-            RecursiveMode::NonRecursive)
-        .unwrap();
-    watcher
-        .watch(
-            std::path::Path::new(&zfsd_upload_frags_dir()),
-            RecursiveMode::Recursive)
-        .unwrap();
+    let mut watcher = or_exit(recommended_watcher(tx), "zfsd failed to watch its directories");
+
+    let follow_digest_dir = zfsd_dir(zfsd_follow_digest_dir());
+    for (dir, mode) in [
+        (zfsd_dir(zfsd_download_digest_dir()), RecursiveMode::NonRecursive),
+        (zfsd_dir(zfsd_upload_digest_dir()), RecursiveMode::NonRecursive),
+        (follow_digest_dir.clone(), RecursiveMode::NonRecursive),
+    ] {
+        or_exit(watcher.watch(&dir, mode), &format!("zfsd failed to watch {:?}", dir));
+    }
 
     tokio::task::spawn(download_sanitizer(z.clone()));
+    tokio::task::spawn(staging_gc());
+    tokio::task::spawn(paused_upload_resumer(z.clone()));
+    if zfs_config().availability.enabled {
+        tokio::task::spawn(track_availability(z.clone()).or_else(|e| async move {
+            log::warn!(target: "zfsd", "Unable to track the availability of the storages: {}", e);
+            Ok::<(), ZfsError>(())
+        }));
+    }
+    if zfs_config().retention.enabled {
+        tokio::task::spawn(retention_scanner(z.clone()));
+    }
+    if zfs_config().swarm.enabled {
+        tokio::task::spawn(serve_swarm(z.clone()).or_else(|e| async move {
+            log::warn!(target: "zfsd", "Swarm queryable failed due to: {}", e);
+            Ok::<(), ZfsError>(())
+        }));
+    }
+    if zfs_config().replicas.check_enabled {
+        tokio::task::spawn(replication_checker(z.clone()));
+    }
+    if zfs_config().scrub.enabled {
+        tokio::task::spawn(scrubber(z.clone()));
+    }
+    tokio::task::spawn(serve_job_status(z.clone()).or_else(|e| async move {
+        log::warn!(target: "zfsd", "Job status queryable failed due to: {}", e);
+        Ok::<(), ZfsError>(())
+    }));
+    recover_uploads(z.clone());
+    recover_follows(z.clone());
 
     log::info!(target:"zfsd", "Up and Running!");
     while let Ok(r) = rx.recv() {
         if let Ok(evt) = r {
-            if evt.kind.is_create() && evt.paths[0].is_file() {
+            // Digests may be written to a temporary file and then renamed
+            let created = evt.kind.is_create()
+                || matches!(evt.kind, EventKind::Modify(ModifyKind::Name(RenameMode::To)));
+            if created && evt.paths[0].is_file() {
                 log::debug!(target: "zfsd", "Received Create Event {:?}", &evt);
                 let path = evt.paths[0].clone();
-                let parent = path.parent().unwrap();
+                let (Some(parent), Some(fpath)) = (path.parent(), path.to_str()) else {
+                    log::warn!(target: "zfsd", "Ignoring the invalid path {:?}", &path);
+                    continue;
+                };
 
-                if parent.ends_with(DOWNLOAD_SUBDIR) {
+                if zfs_is_tmp_path(&path.to_string_lossy()) {
+                    log::trace!(target: "zfsd", "Ignoring temporary file {:?}", &path);
+                } else if parent.ends_with(DOWNLOAD_SUBDIR) {
                     log::info!(target: "zfsd", "Downloading {:?}", &path);
-                    tokio::task::spawn(
-                        zfs::download(z.clone(), path.clone()).or_else(
-                            |e| async move {
-                                log::warn!("Failed to download due to: {}", e);
-                                Ok::<(), String>(())
-                            },
-                        ),
+                    let job = zfs::download(z.clone(), path.clone()).or_else(
+                        |e| async move {
+                            log::warn!("Failed to download due to: {}", e);
+                            Ok::<(), ZfsError>(())
+                        },
                     );
+                    tokio::task::spawn(job);
                 } else if parent.ends_with(UPLOAD_SUBDIR) {
                     log::info!(target: "zfsd","Fragmenting {:?}", &path);
-                    let p = path.to_str().unwrap().to_string();
-                    let _ignore = tokio::task::spawn(zfs::fragment_from_digest(p).or_else(
+                    let job = zfs::fragment_from_digest(z.clone(), fpath.to_string()).or_else(
                         |e| async move {
                             log::warn!("Failed to fragment due to: {}", e);
-                            Ok::<(), String>(())
+                            Ok::<(), ZfsError>(())
                         },
-                    ));
+                    );
+                    let _ignore = tokio::task::spawn(job);
+                } else if parent == follow_digest_dir {
+                    log::info!(target: "zfsd", "Following {:?}", &path);
+                    spawn_follow(z.clone(), path.clone());
                 } else {
-                    let fpath = path.to_str().unwrap();
-                    if !fpath.contains(DOWNLOAD_SUBDIR) {
-                        match fpath.find(FRAGS_SUBDIR) {
-                            Some(_) => {
-                                match zfsd_upload_frag_dir_to_key(fpath) {
-                                    Some(key_suffix) => {
-                                        let key = zfs_key(&key_suffix);
-                                        log::debug  !(target: "zfsd", "Uploading fragment : {:?} as {:?}", path, &key);
-                                        upload_fragment(&z, fpath, &key).await;
-                                    }
-                                    None => {
-                                        log::warn!(target: "zfsd", "Unable to extract key from {}", fpath);
-                                    }
-                                }
-                            }
-                            None => {
-                                log::warn!(target: "zfsd", "Ignoring {:?} path...", &path);
-                            }
-                        }
-                    }
+                    log::warn!(target: "zfsd", "Ignoring {:?} path...", &path);
                 }
             } else {
                 log::debug!(target: "zfsd", "Ignoring create event for directory {:?}", &evt);
@@ -111,7 +196,10 @@ fn parse_args() -> zenoh::config::Config {
             "-m, --mode=[MODE] 'The zenoh session mode (peer by default)."
         ).possible_values(&["peer", "client"]))
         .arg(Arg::from_usage(
-            "-c, --config=[FILE]  'A zenoh configuration file.'",
+            "-c, --config=[FILE]  'A zenoh configuration file, it replaces the one embedded in the zfsd configuration.'",
+        ))
+        .arg(Arg::from_usage(
+            "-f, --zfsd-config=[FILE]  'A zfsd configuration file (see zfsd.json5).'",
         ))
         .arg(Arg::from_usage(
             "-s, --fragment-size=[size]  'The maximun size used for fragmenting for files.'",
@@ -119,28 +207,72 @@ fn parse_args() -> zenoh::config::Config {
         .arg(Arg::from_usage(
             "-r, --remote-endpoints=[ENDPOINTS]...  'The locators for a remote zenoh endpoint such as a routers'",
         ))
+        .arg(Arg::from_usage(
+            "--generate-key=[FILE]  'Generates an ed25519 signing key into FILE, prints its public key and exits.'",
+        ))
         .get_matches();
 
-    let mut config = args
-        .value_of("config")
-        .map_or_else(| | { zenoh::Config::default() }, |conf_file| {
-            zenoh::Config::from_file(conf_file).unwrap()
+    if let Some(key_file) = args.value_of("generate-key") {
+        match zfs_generate_signing_key(key_file) {
+            Ok(public_key) => {
+                println!("{}", public_key);
+                exit(0);
+            }
+            Err(e) => {
+                println!("Unable to generate the key: {}", e);
+                exit(-1);
+            }
+        }
+    }
+
+    let mut zfsd_config = args
+        .value_of("zfsd-config")
+        .map_or_else(ZfsdConfig::default, |conf_file| {
+            ZfsdConfig::from_file(conf_file).unwrap_or_else(|e| {
+                println!("Unable to load {}: {}", conf_file, e);
+                exit(-1);
+            })
         });
 
-    if let Some(mode) = args.value_of("mode") {
-        if mode == "peer" {
-            config.set_mode(Some(WhatAmI::Peer)).unwrap();
-        } else if mode == "client" {
-            config.set_mode(Some(WhatAmI::Client)).unwrap();
-        } else {
-            println!("Invalid mode: {}", mode);
-            exit(-1);
+    if let Some(size) = args.value_of("fragment-size") {
+        match size.parse::<usize>() {
+            Ok(n) if n > 0 => zfsd_config.fragmentation.fragment_size = n,
+            _ => {
+                println!("Invalid fragment size: {}", size);
+                exit(-1);
+            }
         }
     }
+
+    let mut config = match args.value_of("config") {
+        Some(conf_file) => zenoh::Config::from_file(conf_file).unwrap_or_else(|e| {
+            println!("Unable to load {}: {}", conf_file, e);
+            exit(-1);
+        }),
+        None => zfsd_config.zenoh_config().unwrap_or_else(|e| {
+            println!("{}", e);
+            exit(-1);
+        }),
+    };
+    or_exit(zfs_set_config(zfsd_config), "Invalid zfsd configuration");
+
+    if let Some(mode) = args.value_of("mode") {
+        let mode = match mode {
+            "peer" => WhatAmI::Peer,
+            "client" => WhatAmI::Client,
+            _ => {
+                println!("Invalid mode: {}", mode);
+                exit(-1);
+            }
+        };
+        or_exit(config.set_mode(Some(mode)), "Unable to set the zenoh mode");
+    }
     if let Some(values) = args.values_of("remote-endpoints") {
-        config.connect.endpoints.set(
-            values.map(|v| v.parse().unwrap()).collect()
-        ).expect("Invalid Endpoints");
+        let endpoints = values
+            .map(|v| v.parse().map_err(|e| format!("{}: {}", v, e)))
+            .collect::<Result<Vec<_>, _>>();
+        let r = config.connect.endpoints.set(or_exit(endpoints, "Invalid endpoint"));
+        or_exit(r.map_err(|_| "unable to set them"), "Invalid endpoints");
     }
 
     config